@@ -1,13 +1,15 @@
 
 use alloc::alloc::{GlobalAlloc, Layout};
-use super::{Locked, align_up};
+use super::{Locked, align_up, grow_heap, realloc_by_copy, Counters, HeapStats, Introspect};
 use core::ptr;
 
 pub struct BumpAlloc {
     heap_start: usize, // Generic heap infos
     heap_end: usize, // Inclusive
     next: usize, // Next block to be allocated
-    allocations: usize // Number of allocated entities
+    allocations: usize, // Number of allocated entities
+    dirty_end: usize, // Memory from there on was never handed out, hence still zeroed
+    counters: Counters,
 }
 
 impl BumpAlloc {
@@ -17,46 +19,116 @@ impl BumpAlloc {
             heap_start: 0,
             heap_end: 0,
             next: 0,
-            allocations: 0
+            allocations: 0,
+            dirty_end: 0,
+            counters: Counters::new(),
         }
     }
     /// Initializes the bump allocator with the given heap bounds
     /// 
-    /// Must only be called ONCE during the lifespan of the allocator
+    /// Must only be called ONCE during the lifespan of the allocator, with zeroed memory (see `alloc_zeroed`),
+    /// as the heap pages are when mapped
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) { // Parameters are hardcoded in allocator/mod.rs
         self.heap_start = heap_start;
         self.heap_end =  heap_start + heap_size; // Exclusive end
         self.next = heap_start; // Alloc default is to point to the first block in the heap, which is logically not allocated
+        self.dirty_end = heap_start;
     }
-}
 
-unsafe impl GlobalAlloc for Locked<BumpAlloc> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut bump_alloc = self.lock();
+    /// Makes sure the heap ends at or after `end`, growing it if needed.
+    fn reserve_until(&mut self, end: usize) -> bool {
+        if end > self.heap_end {
+            // Grows the heap just enough for this allocation, if possible
+            match grow_heap(self.heap_end, end - self.heap_end) {
+                Some(grown) => self.heap_end += grown,
+                None => return false, // Alloc error
+            }
+        }
+        end <= self.heap_end // The heap may have grown, but not enough
+    }
 
-        let alloc_start = align_up(bump_alloc.next, layout.align());
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
         let alloc_end = match alloc_start.checked_add(layout.size()) {
             Some(end) => end,
             None => return ptr::null_mut(),
         };
 
-        if alloc_end > bump_alloc.heap_end {
-            ptr::null_mut() // Alloc error
+        if !self.reserve_until(alloc_end) {
+            ptr::null_mut()
         }
 
         else {
-            bump_alloc.next = alloc_end;
-            bump_alloc.allocations += 1;
+            self.next = alloc_end;
+            self.dirty_end = self.dirty_end.max(alloc_end);
+            self.allocations += 1;
+            self.counters.record_alloc(layout.size());
             alloc_start as *mut u8 // Reurn the allocated slab
         }
     }
+}
+
+impl Introspect for BumpAlloc {
+    /// The only free region is the one between `next` and the heap end
+    fn stats(&self) -> HeapStats {
+        let free_bytes = self.heap_end - self.next;
+        HeapStats {
+            free_regions: Some(if free_bytes > 0 { 1 } else { 0 }),
+            free_bytes,
+            largest_free_region: Some(free_bytes),
+            ..self.counters.stats()
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BumpAlloc> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut bump_alloc = self.lock();
 
+        bump_alloc.counters.record_dealloc(layout.size());
         bump_alloc.allocations -= 1;
         if bump_alloc.allocations == 0 {
             bump_alloc.next = bump_alloc.heap_start;
         }
     }
+
+    /// Only the memory which was handed out before is zeroed, the rest of the heap being zeroed already.
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let mut bump_alloc = self.lock();
+        let dirty_end = bump_alloc.dirty_end;
+        let ptr = bump_alloc.allocate(layout);
+        if !ptr.is_null() && (ptr as usize) < dirty_end {
+            ptr::write_bytes(ptr, 0, layout.size().min(dirty_end - ptr as usize));
+        }
+        ptr
+    }
+
+    /// The last allocation is resized in place by moving `next`, and any other one can shrink in place,
+    /// its tail being lost until every allocation is freed (as any freed memory).
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let mut bump_alloc = self.lock();
+        let alloc_start = ptr as usize;
+
+        if alloc_start + layout.size() == bump_alloc.next {
+            let alloc_end = match alloc_start.checked_add(new_size) {
+                Some(end) if bump_alloc.reserve_until(end) => end,
+                _ => return ptr::null_mut(), // Moving it would not need less memory
+            };
+            bump_alloc.next = alloc_end;
+            bump_alloc.dirty_end = bump_alloc.dirty_end.max(alloc_end);
+            bump_alloc.counters.record_realloc(layout.size(), new_size);
+            return ptr;
+        }
+        if new_size <= layout.size() {
+            bump_alloc.counters.record_realloc(layout.size(), new_size);
+            return ptr;
+        }
+
+        drop(bump_alloc);
+        realloc_by_copy(self, ptr, layout, new_size)
+    }
 }
@@ -0,0 +1,295 @@
+// Debugging wrapper around any of the heap allocators, enabled for the global one with the `alloc-debug` feature.
+// Each allocation is surrounded by red zones, guard bytes checked when it is freed to catch overflows, and freed
+// memory is filled with a poison pattern so that uses after free stand out.
+// Live allocations are tracked in a fixed-size hash table (the heap can obviously not be used for that), which
+// catches double frees, frees of pointers never allocated, and frees with another `Layout` than the allocation's.
+// Errors are reported over serial, and the faulty free is then ignored or fixed up instead of corrupting the
+// backend. Reallocations go through the checked allocation and free rather than the backend's own `realloc`, so that
+// the red zones and the table follow the block.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+use core::ptr;
+
+use super::{align_up, realloc_by_copy, Locked};
+use crate::serial_println;
+
+const RED_ZONE: usize = 16; // Holds the backends' free list nodes, which thus never overwrite the poison
+const RED_ZONE_BYTE: u8 = 0xfd;
+const FREED_BYTE: u8 = 0xdd;
+const MAX_TRACKED: usize = 4096; // Allocations beyond that are served, but not checked when freed
+const SLOTS: usize = 2 * MAX_TRACKED;
+const MAX_USED: usize = SLOTS * 3 / 4; // Freed slots are cleared past that, so that probe sequences stay short
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Empty, // Ends a probe sequence
+    Live,
+    Freed, // Kept to tell double frees from invalid ones, until the slot is reused or freed slots are cleared
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    state: State,
+    ptr: usize,
+    layout: Layout,
+}
+
+/// Open addressing hash table of the allocations, keyed by the address handed out
+struct Tracker {
+    entries: [Entry; SLOTS],
+    live: usize,
+    used: usize, // Slots which are not `Empty`
+    overflowed: bool, // Some allocation could not be tracked, so unknown pointers are not errors anymore
+    errors: usize,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        const EMPTY: Entry = Entry {
+            state: State::Empty,
+            ptr: 0,
+            layout: Layout::new::<u8>(),
+        };
+        Tracker {
+            entries: [EMPTY; SLOTS],
+            live: 0,
+            used: 0,
+            overflowed: false,
+            errors: 0,
+        }
+    }
+
+    /// Yields the slot indices to probe for `ptr`, starting from its hash.
+    fn probe(ptr: usize) -> impl Iterator<Item = usize> {
+        let start = (ptr / 8).wrapping_mul(0x9e37_79b9_7f4a_7c15) % SLOTS;
+        (0..SLOTS).map(move |i| (start + i) % SLOTS)
+    }
+
+    /// Returns the slot holding `ptr`, live or freed, if any.
+    fn find(&self, ptr: usize) -> Option<usize> {
+        Self::probe(ptr)
+            .take_while(|&i| self.entries[i].state != State::Empty)
+            .find(|&i| self.entries[i].ptr == ptr)
+    }
+
+    fn insert(&mut self, ptr: usize, layout: Layout) {
+        if self.live == MAX_TRACKED {
+            self.overflowed = true;
+            return;
+        }
+        // Reuses the slot of the same pointer if it was freed before, or else the first free slot on the way
+        let i = self.find(ptr).unwrap_or_else(|| {
+            Self::probe(ptr).find(|&i| self.entries[i].state != State::Live).unwrap() // At most half are live
+        });
+        match self.entries[i].state {
+            State::Empty => {
+                self.live += 1;
+                self.used += 1;
+            }
+            State::Freed => self.live += 1,
+            State::Live => {} // Handed out twice, which only an untracked free can explain
+        }
+        self.entries[i] = Entry { state: State::Live, ptr, layout };
+
+        if self.used > MAX_USED {
+            self.clear_freed();
+        }
+    }
+
+    fn mark_freed(&mut self, i: usize) {
+        self.entries[i].state = State::Freed;
+        self.live -= 1;
+    }
+
+    /// Empties the freed slots, which would otherwise pile up until every lookup probes the whole table, and moves
+    /// the live entries back over the holes this leaves in their probe sequences.
+    ///
+    /// Double frees of the pointers forgotten this way are then reported as frees of pointers never allocated.
+    fn clear_freed(&mut self) {
+        for entry in self.entries.iter_mut().filter(|entry| entry.state == State::Freed) {
+            entry.state = State::Empty;
+        }
+        self.used = self.live;
+
+        // Walking from an empty slot, each live entry moves to the first empty slot of its probe sequence, which is
+        // never past the one it was in, so that the entries walked before it stay reachable
+        let start = self.entries.iter().position(|entry| entry.state == State::Empty).unwrap();
+        for i in (start + 1..start + SLOTS).map(|i| i % SLOTS) {
+            if self.entries[i].state == State::Live {
+                let entry = self.entries[i];
+                self.entries[i].state = State::Empty;
+                let slot = Self::probe(entry.ptr).find(|&j| self.entries[j].state == State::Empty).unwrap();
+                self.entries[slot] = entry;
+            }
+        }
+    }
+
+    fn report(&mut self, args: core::fmt::Arguments) {
+        self.errors += 1;
+        serial_println!("[heap] {}", args);
+    }
+}
+
+/// Wraps an allocator with red zones, poisoning and free checks (see the module header).
+///
+/// Dereferences to the wrapped allocator, so that it is initialized and inspected as usual.
+pub struct DebugAlloc<T> {
+    inner: Locked<T>,
+    tracker: spin::Mutex<Tracker>,
+}
+
+impl<T> DebugAlloc<T> {
+    pub const fn new(inner: T) -> Self {
+        DebugAlloc {
+            inner: Locked::new(inner),
+            tracker: spin::Mutex::new(Tracker::new()),
+        }
+    }
+
+    /// Number of errors reported so far
+    pub fn errors(&self) -> usize {
+        self.tracker.lock().errors
+    }
+
+    /// Layout of the block asked to the wrapped allocator for `layout`, and the offset of the user data in it
+    fn outer_layout(layout: Layout) -> (Layout, usize) {
+        let offset = align_up(RED_ZONE, layout.align());
+        let size = offset + layout.size() + RED_ZONE;
+        (Layout::from_size_align(size, layout.align()).unwrap(), offset)
+    }
+}
+
+impl<T> Deref for DebugAlloc<T> {
+    type Target = Locked<T>;
+
+    fn deref(&self) -> &Locked<T> {
+        &self.inner
+    }
+}
+
+unsafe impl<T> GlobalAlloc for DebugAlloc<T>
+where
+    Locked<T>: GlobalAlloc,
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, offset) = Self::outer_layout(layout);
+        let block = self.inner.alloc(outer);
+        if block.is_null() {
+            return block;
+        }
+
+        block.write_bytes(RED_ZONE_BYTE, offset);
+        let ptr = block.add(offset);
+        ptr.add(layout.size()).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        self.tracker.lock().insert(ptr as usize, layout);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut tracker = self.tracker.lock();
+
+        // Frees with the layout the allocation was made with, whatever the caller says
+        let layout = match tracker.find(ptr as usize) {
+            Some(i) if tracker.entries[i].state == State::Live => {
+                let entry = tracker.entries[i];
+                if entry.layout != layout {
+                    tracker.report(format_args!(
+                        "{:p} freed with {:?}, but allocated with {:?}",
+                        ptr, layout, entry.layout
+                    ));
+                }
+                tracker.mark_freed(i);
+                entry.layout
+            }
+            Some(_) => {
+                tracker.report(format_args!("{:p} freed twice", ptr));
+                return;
+            }
+            None if tracker.overflowed => layout, // Possibly an untracked allocation
+            None => {
+                tracker.report(format_args!("{:p} freed, but was never allocated", ptr));
+                return;
+            }
+        };
+
+        let (outer, offset) = Self::outer_layout(layout);
+        let block = ptr.sub(offset);
+        let front = core::slice::from_raw_parts(block, offset);
+        let back = core::slice::from_raw_parts(ptr.add(layout.size()), RED_ZONE);
+        if let Some(i) = front.iter().rposition(|&b| b != RED_ZONE_BYTE) {
+            tracker.report(format_args!("{:p} underflowed by {} bytes", ptr, offset - i));
+        }
+        if let Some(i) = back.iter().rposition(|&b| b != RED_ZONE_BYTE) {
+            tracker.report(format_args!("{:p} overflowed by {} bytes", ptr, i + 1));
+        }
+        drop(tracker);
+
+        ptr::write_bytes(block, FREED_BYTE, outer.size());
+        self.inner.dealloc(block, outer);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc_by_copy(self, ptr, layout, new_size)
+    }
+}
+
+#[test_case]
+fn bad_frees_are_caught() {
+    use super::linked_list::LinkedListAlloc;
+
+    const ARENA_SIZE: usize = 4096;
+    static ALLOCATOR: DebugAlloc<LinkedListAlloc> = DebugAlloc::new(LinkedListAlloc::new());
+
+    let arena_start = super::test_arena(ARENA_SIZE);
+    unsafe { ALLOCATOR.lock().init(arena_start, ARENA_SIZE) };
+    let layout = Layout::from_size_align(40, 8).unwrap();
+
+    unsafe {
+        let ptr = ALLOCATOR.alloc(layout);
+        ptr.write_bytes(0x11, layout.size());
+        assert_eq!(ALLOCATOR.errors(), 0);
+        ALLOCATOR.dealloc(ptr, layout);
+        assert_eq!(ALLOCATOR.errors(), 0);
+        assert!(core::slice::from_raw_parts(ptr, layout.size()).iter().all(|&b| b == FREED_BYTE));
+
+        ALLOCATOR.dealloc(ptr, layout); // Double free
+        assert_eq!(ALLOCATOR.errors(), 1);
+
+        let ptr = ALLOCATOR.alloc(layout);
+        ptr.add(layout.size()).write(0); // One byte too far
+        ALLOCATOR.dealloc(ptr, Layout::from_size_align(32, 8).unwrap()); // And the wrong layout
+        assert_eq!(ALLOCATOR.errors(), 3);
+
+        ALLOCATOR.dealloc((arena_start + 64) as *mut u8, layout); // Never allocated
+        assert_eq!(ALLOCATOR.errors(), 4);
+    }
+
+    // Nothing was freed twice, so the backend is back to a single free region
+    let stats = ALLOCATOR.stats();
+    assert_eq!(stats.free_regions, Some(1));
+    assert_eq!(stats.free_bytes, ARENA_SIZE);
+}
+
+#[test_case]
+fn freed_slots_are_cleared() {
+    static TRACKER: spin::Mutex<Tracker> = spin::Mutex::new(Tracker::new());
+
+    let mut tracker = TRACKER.lock();
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    let kept = 0x1000;
+    tracker.insert(kept, layout);
+    // Many more allocations than slots, each freed right away as a short-lived one would be
+    for ptr in (0..3 * SLOTS).map(|i| 0x10_0000 + i * 16) {
+        tracker.insert(ptr, layout);
+        let i = tracker.find(ptr).unwrap();
+        tracker.mark_freed(i);
+        assert!(tracker.used <= MAX_USED);
+    }
+
+    assert_eq!(tracker.live, 1);
+    let i = tracker.find(kept).expect("live allocation lost when clearing freed slots");
+    assert!(tracker.entries[i].state == State::Live);
+    assert!(tracker.entries.iter().any(|entry| entry.state == State::Empty));
+    assert_eq!(tracker.errors, 0);
+}
@@ -0,0 +1,54 @@
+// Wrapper around the heap of the `linked_list_allocator` crate, so that it can be selected as the global allocator
+// (feature `alloc-external`) and compared against ours, with the same growth and statistics support.
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
+
+use super::{allocate_growing, Counters, HeapStats, Introspect, Locked};
+
+pub struct ExternalAlloc {
+    heap: Heap,
+    counters: Counters,
+}
+
+impl ExternalAlloc {
+    pub const fn new() -> Self {
+        ExternalAlloc {
+            heap: Heap::empty(),
+            counters: Counters::new(),
+        }
+    }
+
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start as *mut u8, heap_size);
+    }
+
+}
+
+impl Introspect for ExternalAlloc {
+    /// The crate does not expose its free list, so only the free bytes are known of it, its regions being unknown.
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            free_bytes: self.heap.free(),
+            ..self.counters.stats()
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<ExternalAlloc> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let ptr = allocate_growing(&mut allocator.heap, layout);
+        if !ptr.is_null() {
+            allocator.counters.record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.heap.deallocate(NonNull::new(ptr).unwrap(), layout);
+        allocator.counters.record_dealloc(layout.size());
+    }
+}
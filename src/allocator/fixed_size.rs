@@ -1,40 +1,80 @@
-// Fixed-size allocation cuts the heap in several fixed-size slabs, joined as a free linked list under a common
-// node size parameter. This allocator provides slab sizes of 8, 16, 32, 64, 128, 256, 512, 1024 and 2048 bytes.
-//The use of powers of 2 permits additional slab spawning  (in case heap runs out of slabs of a prticular size),
-// so that splitting a bigger free block into two smaller is feasible. Ths permits to avoid using a fallback allocator
-// in case of a lack of free slabs. However, this fallback solution maybe used for large block allocation, which are
-// rare among kernel allocation.
-// The fixed-size pattern provides allocation and deallocation methods which are O(1), as no traversal
-// of the free-list is needed.
+// Fixed-size allocation serves each allocation from a block of the smallest fitting size class, among 8, 16, 32, 64,
+// 128, 256, 512, 1024, 2048 and 4096 bytes. Bigger allocations, which are rare among kernel allocations, are served
+// by the fallback allocator.
+// Blocks of up to `MAX_SLAB_BLOCK` bytes are carved out of slabs: pages taken from the fallback allocator, and
+// dedicated to a single size class. Each slab keeps its own free list of blocks, and a slab whose blocks are all free
+// again may be given back to the fallback allocator, so that memory does not remain stuck in a size class forever.
+// Bigger blocks are taken from the fallback allocator one at a time and given back as soon as they are freed, as the
+// header of a slab would otherwise take up a whole block of its page.
+// One empty slab per class is kept around, so that a single block being allocated and freed over and over does not
+// allocate a whole slab each time. The others are given back right away, and `shrink` gives back the kept ones.
+// Blocks come from memory the fallback allocator may have handed out before, which is not tracked, so `alloc_zeroed`
+// zeroes every block, unlike with the bump and linked-list allocators.
+// Allocation and deallocation in slabs are O(1), as no traversal of any list is needed: the slab of a block is found
+// by aligning its address down to the slab size, slabs being aligned on their size.
 
-use core::{alloc::{GlobalAlloc, Layout}, ptr::{self, NonNull}, mem};
+use core::{alloc::{GlobalAlloc, Layout}, mem, ptr::{self, NonNull}};
 
-use super::Locked;
+use super::{align_up, allocate_growing, realloc_by_copy, Counters, HeapStats, Introspect, Locked};
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
 // Slab size does not go under 8B, because at least a 64-bit pointer must fit into it.
 
+const SLAB_SIZE: usize = 4096;
+const MAX_SLAB_BLOCK: usize = 512; // Biggest block size served from slabs, the header taking one block of each slab
+
+/// Whether the blocks of the class at `index` are carved out of slabs, rather than taken from the fallback allocator
+fn in_slabs(index: usize) -> bool {
+    BLOCK_SIZES[index] <= MAX_SLAB_BLOCK
+}
+
 struct Node {
     next: Option<&'static mut Node>,
     // No size, because all nodes under a common size per list
 }
 
+/// Header written at the start of each slab, followed by its blocks
+struct Slab {
+    // Neighbours in the list of partial slabs of the class, null if none
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: Option<&'static mut Node>,
+    in_use: usize,
+}
+
+struct SizeClass {
+    partial: *mut Slab, // Slabs with at least one free block, empty ones included, if in slabs
+    slabs: usize,
+    empty_slabs: usize,
+    in_use: usize, // Blocks currently allocated
+}
+
 fn list_index(layout: &Layout) -> Option<usize> {
     let block_size = layout.size().max(layout.align()); // The block size is its alignment
     BLOCK_SIZES.iter().position(|&fb_size| fb_size >= block_size)
 }
 
 pub struct FixedSizeAlloc {
-    list_heads: [Option<&'static mut Node>; BLOCK_SIZES.len()],
+    classes: [SizeClass; BLOCK_SIZES.len()],
     fallback_alloc: linked_list_allocator::Heap,
+    counters: Counters,
 }
 
+// Slabs are only reached through the allocator, itself behind a lock
+unsafe impl Send for FixedSizeAlloc {}
+
 impl FixedSizeAlloc {
     pub const fn new() -> Self {
-        const EMPTY:  Option<&'static mut Node> = None;
+        const EMPTY: SizeClass = SizeClass {
+            partial: ptr::null_mut(),
+            slabs: 0,
+            empty_slabs: 0,
+            in_use: 0,
+        };
         FixedSizeAlloc {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            classes: [EMPTY; BLOCK_SIZES.len()],
             fallback_alloc: linked_list_allocator::Heap::empty(),
+            counters: Counters::new(),
         }
     }
 
@@ -42,70 +82,275 @@ impl FixedSizeAlloc {
         self.fallback_alloc.init(heap_start as *mut u8, heap_size);
     }
 
-    /// Allocates using the fallback allocator.
+    /// Gives back every empty slab to the fallback allocator, e.g. under memory pressure.
+    ///
+    /// Returns the number of bytes given back.
+    pub fn shrink(&mut self) -> usize {
+        let mut released = 0;
+        for index in 0..BLOCK_SIZES.len() {
+            let mut slab = self.classes[index].partial;
+            while !slab.is_null() {
+                let next = unsafe { (*slab).next };
+                if unsafe { (*slab).in_use } == 0 {
+                    unsafe { self.release_slab(index, slab) };
+                    released += SLAB_SIZE;
+                }
+                slab = next;
+            }
+        }
+        released
+    }
+
+    /// Allocates using the fallback allocator, growing its heap once if it is full,
+    /// and giving back empty slabs if it is still out of memory.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_alloc.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        let ptr = allocate_growing(&mut self.fallback_alloc, layout);
+        if ptr.is_null() && self.shrink() > 0 {
+            return allocate_growing(&mut self.fallback_alloc, layout);
+        }
+        ptr
+    }
+
+    /// Takes a block of the class from the fallback allocator, for classes not in slabs.
+    fn alloc_large_block(&mut self, index: usize, align: usize) -> *mut u8 {
+        let ptr = self.fallback_alloc(Layout::from_size_align(BLOCK_SIZES[index], align).unwrap());
+        if !ptr.is_null() {
+            self.classes[index].in_use += 1;
+        }
+        ptr
+    }
+
+    /// Gives a block of a class not in slabs back to the fallback allocator.
+    unsafe fn dealloc_large_block(&mut self, index: usize, ptr: *mut u8, align: usize) {
+        let layout = Layout::from_size_align_unchecked(BLOCK_SIZES[index], align);
+        self.fallback_alloc.deallocate(NonNull::new(ptr).unwrap(), layout);
+        self.classes[index].in_use -= 1;
+    }
+
+    /// Pops a block from the first partial slab of the class, creating a new slab if there is none.
+    fn alloc_block(&mut self, index: usize) -> *mut u8 {
+        if self.classes[index].partial.is_null() && self.new_slab(index).is_null() {
+            return ptr::null_mut();
+        }
+        let class = &mut self.classes[index];
+        let slab = unsafe { &mut *class.partial };
+        if slab.in_use == 0 {
+            class.empty_slabs -= 1;
+        }
+        let block = slab.free.take().expect("partial slab without free block");
+        slab.free = block.next.take();
+        slab.in_use += 1;
+        class.in_use += 1;
+
+        if slab.free.is_none() { // Now full, and only found back through its blocks
+            unsafe { self.unlink(index, slab) };
         }
+        block as *mut Node as *mut u8
+    }
+
+    /// Pushes a block back to its slab's free list, and gives the slab back if it is now empty
+    /// and the class already has an empty slab.
+    unsafe fn dealloc_block(&mut self, index: usize, ptr: *mut u8) {
+        // verify that block has size and alignment required for storing node
+        assert!(mem::size_of::<Node>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<Node>() <= BLOCK_SIZES[index]);
+
+        let slab_ptr = (ptr as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        let slab = &mut *slab_ptr;
+        let was_full = slab.free.is_none();
+
+        // store node, thus freeing this area
+        let new_node_ptr = ptr as *mut Node;
+        new_node_ptr.write(Node { next: slab.free.take() });
+        slab.free = Some(&mut *new_node_ptr);
+        slab.in_use -= 1;
+        self.classes[index].in_use -= 1;
+
+        if was_full {
+            self.push(index, slab_ptr);
+        }
+        if slab.in_use == 0 {
+            self.classes[index].empty_slabs += 1;
+            if self.classes[index].empty_slabs > 1 {
+                self.release_slab(index, slab_ptr);
+            }
+        }
+    }
+
+    /// Carves a new slab into blocks of the class, and pushes it to the partial slabs.
+    fn new_slab(&mut self, index: usize) -> *mut Slab {
+        let block_size = BLOCK_SIZES[index];
+        let size = SLAB_SIZE;
+        let start = self.fallback_alloc(Layout::from_size_align(size, size).unwrap());
+        if start.is_null() {
+            return ptr::null_mut();
+        }
+
+        // Blocks are chained from the end, so that the first one ends up at the front of the free list
+        let first_block = start as usize + align_up(mem::size_of::<Slab>(), block_size);
+        let mut free = None;
+        for block in (first_block..start as usize + size).step_by(block_size).rev() {
+            let node_ptr = block as *mut Node;
+            unsafe {
+                node_ptr.write(Node { next: free });
+                free = Some(&mut *node_ptr);
+            }
+        }
+
+        let slab = start as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                free,
+                in_use: 0,
+            });
+            self.push(index, slab);
+        }
+        self.classes[index].slabs += 1;
+        self.classes[index].empty_slabs += 1;
+        slab
+    }
+
+    /// Unlinks an empty slab and gives its memory back to the fallback allocator.
+    unsafe fn release_slab(&mut self, index: usize, slab: *mut Slab) {
+        self.unlink(index, slab);
+        self.classes[index].slabs -= 1;
+        self.classes[index].empty_slabs -= 1;
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        self.fallback_alloc.deallocate(NonNull::new_unchecked(slab as *mut u8), layout);
+    }
+
+    /// Pushes a slab to the front of the partial slabs of the class.
+    unsafe fn push(&mut self, index: usize, slab: *mut Slab) {
+        let head = self.classes[index].partial;
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = head;
+        if !head.is_null() {
+            (*head).prev = slab;
+        }
+        self.classes[index].partial = slab;
+    }
+
+    /// Removes a slab from the partial slabs of the class, wherever it is.
+    unsafe fn unlink(&mut self, index: usize, slab: *mut Slab) {
+        let (prev, next) = ((*slab).prev, (*slab).next);
+        if prev.is_null() {
+            self.classes[index].partial = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = ptr::null_mut();
+    }
+}
+
+impl Introspect for FixedSizeAlloc {
+    /// Free blocks of the size classes are not counted in the free bytes, which are the fallback allocator's ones.
+    /// As with `ExternalAlloc`, the free list of the fallback allocator is not exposed, so its regions are unknown.
+    fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            free_bytes: self.fallback_alloc.free(),
+            ..self.counters.stats()
+        };
+
+        for (index, class) in stats.size_classes.iter_mut().enumerate() {
+            class.in_use = self.classes[index].in_use;
+            if in_slabs(index) {
+                let block_size = BLOCK_SIZES[index];
+                let blocks_per_slab = (SLAB_SIZE - align_up(mem::size_of::<Slab>(), block_size)) / block_size;
+                class.slabs = self.classes[index].slabs;
+                class.free = class.slabs * blocks_per_slab - class.in_use;
+            }
+        }
+        stats
     }
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeAlloc> {
-    /// Retrieves a (potential) node from the corresponding size linked list, so that is is
-    /// popped from the front of the free list. If the chosen free list is empty (which is the initial case)
-    /// blocks are lazily allocated for that list, and remain maintained by the fallback even when `dealloc`
-    /// is called. This way, the fixed size allocator is built upon its fallback allocator, taking profit of the latter's
-    /// allocation. 
-    /// One consequence of this is that fixed-size deallocation is "virtual", in the sense that the actual blocks are not
-    /// freed other than from the free-list. The fallback allocator does not free the blocks at each deallocation.
-    /// 
-    /// One exception is if dealloc is used in another context than on one fixed-size linked-list. In this case, the fallback
-    /// allocator physically deallocates the given slab.
-    /// 
-    /// **Performance overhead**: initial allocations may suffer from low performance due to the systematic use of the fallback
+    /// Pops a block from a slab of the corresponding size class. If the class has no slab with a free block
+    /// (which is the initial case), a new slab is taken from the fallback allocator.
+    /// Blocks of the classes not in slabs, and allocations too big for any class, are directly made by the fallback
     /// allocator.
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(head) => {
-                        allocator.list_heads[index] = head.next.take();
-                        head as *mut Node as *mut u8
-                    }
-                    None => { // lazy alloc
-                        let block_size = BLOCK_SIZES[index];
-                        let block_align = block_size;
-                        let alloc_layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(alloc_layout)
-                    }
-                }
-            } 
+        let ptr = match list_index(&layout) {
+            Some(index) if in_slabs(index) => allocator.alloc_block(index),
+            Some(index) => allocator.alloc_large_block(index, layout.align()),
             None => allocator.fallback_alloc(layout) // Other size alloc
+        };
+        if !ptr.is_null() {
+            allocator.counters.record_alloc(layout.size());
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.counters.record_dealloc(layout.size());
         match list_index(&layout) {
-            Some(index) => { // virtual dealloc
-                let new_node = Node {
-                    next: allocator.list_heads[index].take(),
-                };
-                // verify that block has size and alignment required for storing node
-                assert!(mem::size_of::<Node>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<Node>() <= BLOCK_SIZES[index]);
-                // store node, thus freeing this area
-                let new_node_ptr = ptr as *mut Node;
-                new_node_ptr.write(new_node);
-                // update the head
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
+            Some(index) if in_slabs(index) => allocator.dealloc_block(index, ptr),
+            Some(index) => allocator.dealloc_large_block(index, ptr, layout.align()),
             None => { // Allocation was not made in a fixed-size compliant manner, but rather by the fallback allocator
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_alloc.deallocate(ptr, layout);
+                allocator.fallback_alloc.deallocate(NonNull::new(ptr).unwrap(), layout);
+            }
+        }
+    }
+
+    /// Keeps the block if the new size falls in the same class, such as when a `Vec` grows by a few elements.
+    /// Other reallocations move the data.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if let (Some(index), Some(new_index)) = (list_index(&layout), list_index(&new_layout)) {
+            if index == new_index {
+                self.lock().counters.record_realloc(layout.size(), new_size);
+                return ptr;
             }
         }
+        realloc_by_copy(self, ptr, layout, new_size)
+    }
+}
+
+impl Locked<FixedSizeAlloc> {
+    /// See `FixedSizeAlloc::shrink`
+    pub fn shrink(&self) -> usize {
+        self.lock().shrink()
+    }
+}
+
+#[test_case]
+fn empty_slabs_are_given_back() {
+    const ARENA_SIZE: usize = 64 * 1024;
+    let allocator = Locked::new(FixedSizeAlloc::new());
+    unsafe { allocator.lock().init(super::test_arena(ARENA_SIZE), ARENA_SIZE) };
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let big = Layout::from_size_align(4096, 8).unwrap();
+    let class = list_index(&small).unwrap();
+
+    // Fills most of the arena with small blocks, spread over many slabs (the heap is not there in lib tests)
+    let mut blocks = [ptr::null_mut(); 600];
+    for block in blocks.iter_mut() {
+        *block = unsafe { allocator.alloc(small) };
+        assert!(!block.is_null());
     }
-}
\ No newline at end of file
+    assert!(allocator.stats().size_classes[class].slabs > 1);
+    for &block in blocks.iter() {
+        unsafe { allocator.dealloc(block, small) };
+    }
+    assert_eq!(allocator.stats().size_classes[class].slabs, 1); // Only one empty slab is kept
+
+    // The memory of the small blocks must now be usable by another class, as it could not fit otherwise
+    for block in blocks[..6].iter_mut() {
+        *block = unsafe { allocator.alloc(big) };
+        assert!(!block.is_null());
+    }
+    for &block in blocks[..6].iter() {
+        unsafe { allocator.dealloc(block, big) };
+    }
+
+    allocator.shrink();
+    assert_eq!(allocator.stats().free_bytes, ARENA_SIZE);
+}
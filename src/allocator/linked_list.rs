@@ -1,6 +1,4 @@
-use crate::{println, serial_println};
-
-use super::{align_up, Locked};
+use super::{align_up, grow_heap, realloc_by_copy, Counters, HeapStats, Introspect, Locked};
 use core::{alloc::{GlobalAlloc, Layout}, mem, ptr};
 
 struct Node {
@@ -24,17 +22,40 @@ impl Node {
 
 pub struct LinkedListAlloc {
     head: Node,
+    heap_end: usize, // Exclusive, where the heap grows from
+    dirty_end: usize, // Memory from there on was never handed out nor holds a node, hence still zeroed
+    counters: Counters,
 }
 
 impl LinkedListAlloc {
     pub const fn new() -> Self {
         Self {
             head: Node::new(0), // Always the tail of the freelist
+            heap_end: 0,
+            dirty_end: 0,
+            counters: Counters::new(),
         }
     }
 
-    pub unsafe fn init(&mut self, heap_start: usize, heap_end: usize) {
-        self.add_free_region(heap_start, heap_end);
+    /// Must be called with zeroed memory (see `alloc_zeroed`), as the heap pages are when mapped.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+        self.heap_end = heap_start + heap_size;
+    }
+
+    /// Grows the heap, so that a region of `size` bytes aligned on `align` may be found.
+    /// The new memory is merged with the last free region if the latter reaches the heap end.
+    ///
+    /// Returns whether the heap could grow.
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        match grow_heap(self.heap_end, size + align) {
+            Some(grown) => {
+                unsafe { self.add_free_region(self.heap_end, grown) };
+                self.heap_end += grown;
+                true
+            }
+            None => false,
+        }
     }
 
     /// Adjust the given layout so that the resulting allocated memory
@@ -50,50 +71,50 @@ impl LinkedListAlloc {
         (size, layout.align())
     }
 
-    /// Adds the given memory region so that the freelist remains sorted
-    /// DO NOT USE
-    //unsafe fn _add_free_region_merged(&mut self, addr: usize, size: usize) {
-    //    // Alignment checks
-    //    assert_eq!(align_up(addr, mem::align_of::<Node>()), addr);
-    //    assert!(size >= mem::size_of::<Node>()); // Is there enough space free to allocate a Node on it?
-
-    //    let current = &mut self.head;
-    //    
-    //    // Searches for a sorted place for the new free space
-    //    while let Some(ref free_region) = current.next {
-    //        if free_region.start_addr() > addr {
-    //            break;
-    //        } 
-    //        // Thus leaves `current` as being the block before the new free block
-    //    }
-
-    //    if current.end_addr() == addr {
-    //        serial_println!("WOW, some blocks were merged at addr {}", addr);
-    //        current.size += size;
-    //        // TODO: impl merging with following block also
-    //    }
-    //    else {
-    //        // We create a new node, not mergeable with any other
-    //        let mut node = Node::new(size);
-    //        node.next = current.next.take(); // Copy + reset to None
-    //        let node_ptr = addr as *mut Node;
-    //        node_ptr.write(node); // Writes the actual free list node
-    //        current.next = Some(&mut *node_ptr); // Current now points to the new free zone
-    //    }        
-    //}
-
-    /// Adds the given memory region to the front of the list.
+    /// Adds the given memory region to the free list, keeping it sorted by address.
+    ///
+    /// The region is merged with its previous and/or next neighbour whenever they are
+    /// contiguous, so that freed blocks coalesce back into larger regions instead of
+    /// fragmenting the heap.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, mem::align_of::<Node>()), addr);
         assert!(size >= mem::size_of::<Node>());
 
-        // create a new list node and append it at the start of the list
-        let mut node = Node::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut Node;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        // Searches for the last region starting before `addr`, or the head if there is none
+        let mut current = &mut self.head;
+        while current.next.as_ref().map_or(false, |next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+
+        // The head is a zero-sized dummy node, and can never be merged with
+        let merge_prev = current.size != 0 && current.end_addr() == addr;
+        let merge_next = current
+            .next
+            .as_ref()
+            .map_or(false, |next| addr + size == next.start_addr());
+
+        if merge_prev {
+            current.size += size;
+            if merge_next { // The freed region fills the gap between two free regions
+                let next = current.next.take().unwrap();
+                current.size += next.size;
+                current.next = next.next.take();
+            }
+        } else {
+            let mut node = Node::new(size);
+            if merge_next { // The next region is absorbed by the new node
+                let next = current.next.take().unwrap();
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = current.next.take();
+            }
+            let node_ptr = addr as *mut Node;
+            node_ptr.write(node); // Writes the actual free list node
+            current.next = Some(&mut *node_ptr);
+            self.dirty_end = self.dirty_end.max(addr + mem::size_of::<Node>());
+        }
     }
 
     /// Looks for a free region with the given size and alignment and removes
@@ -126,8 +147,14 @@ impl LinkedListAlloc {
     /// given a region and a size of allocation as well as alignment constraints.
     /// The allocated region should either fit perfectly, thus leaving no excess free space,
     /// or leave at least `size_of::<Node>()` bytes of excess for the creation of a new Node.
+    /// The same goes for the padding in front of the allocation, which is given back to the free list.
     fn alloc_from_region(region: &Node, size: usize, align: usize) -> Option<usize> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let padding = alloc_start - region.start_addr();
+        if padding > 0 && padding < mem::size_of::<Node>() {
+            // Not enough room for a Node in front, so skip to the next aligned address that leaves some
+            alloc_start = align_up(region.start_addr() + mem::size_of::<Node>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size)?;
 
         if alloc_end > region.end_addr() {
@@ -145,39 +172,270 @@ impl LinkedListAlloc {
     }
 }
 
-unsafe impl GlobalAlloc for Locked<LinkedListAlloc> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+impl LinkedListAlloc {
+    /// Allocates a region fitting `layout` from the first suitable free region, growing the heap if there is none.
+    ///
+    /// Returns the region along with the number of its first bytes which may not be zeroed, as they were handed out
+    /// or held a node before.
+    unsafe fn allocate(&mut self, layout: Layout) -> (*mut u8, usize) {
         let (size, align) = LinkedListAlloc::size_align(layout);
-        let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            // These are calculated again to check whether it allocates or not a new Node for the potential
-            // excess memory.
-            // Indeed, as `find_region` succeeded at this point, it is impossible that 0<=excess<sizeof(Node), 
+        let mut found = self.find_region(size, align);
+        if found.is_none() && self.grow(size, align) {
+            found = self.find_region(size, align);
+        }
+
+        if let Some((region, alloc_start)) = found {
+            // These are calculated again to check whether it allocates or not new Nodes for the potential
+            // padding and excess memory.
+            // Indeed, as `find_region` succeeded at this point, it is impossible that 0<padding/excess<sizeof(Node),
             // so no security check occurs.
+            // The region bounds are read before any new Node overwrites the elected one.
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
             let alloc_end = alloc_start.checked_add(size).expect("overflow in alloc_end calculation");
-            let excess_size = region.end_addr() - alloc_end;
+            // Taken before the nodes of the padding and the excess are written, which lie out of the allocation
+            let dirty = self.dirty_end.clamp(alloc_start, alloc_end) - alloc_start;
+            self.dirty_end = self.dirty_end.max(alloc_end);
 
-            // Allocates a new free region if possible
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size); // Add the (possibly tiny) region to free list
+            // Gives back the padding in front of the allocation, if any
+            if alloc_start > region_start {
+                self.add_free_region(region_start, alloc_start - region_start);
             }
-            // Trolling (but for info!)
-            else if excess_size == mem::size_of::<Node>() {
-                println!("Excess was perfectly fit for a new Node! Hooray!"); // To be removed :)
+            // Allocates a new free region if possible
+            if region_end > alloc_end {
+                self.add_free_region(alloc_end, region_end - alloc_end);
             }
-            alloc_start as *mut u8
+            self.counters.record_alloc(layout.size());
+            (alloc_start as *mut u8, dirty)
         } else {
-            ptr::null_mut() // No free region was found :(
+            (ptr::null_mut(), 0) // No free region was found :(
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+    /// Gives back a region allocated by `allocate` with the same `layout`.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
         // perform layout adjustments
         let (size, _) = LinkedListAlloc::size_align(layout);
-        // Adds this zone to the free list
-        self.lock().add_free_region(ptr as usize, size);
-        println!("Object at addr {} was dropped!", ptr as usize);
+        // Gives this zone back to the free list, merging it with its free neighbours
+        self.add_free_region(ptr as usize, size);
+        self.counters.record_dealloc(layout.size());
         // Now free! (not cleared)
     }
+
+    /// Resizes in place a region allocated by `allocate` with `layout`, so that it holds `new_size` bytes.
+    ///
+    /// Shrinking gives back the tail of the region, and growing takes the front of the free region right after it,
+    /// growing the heap first if that free region (or the allocated one) reaches the heap end.
+    /// Returns whether the region could be resized, the caller having to move it otherwise.
+    unsafe fn reallocate(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let (old_size, _) = LinkedListAlloc::size_align(layout);
+        let (size, _) = LinkedListAlloc::size_align(Layout::from_size_align_unchecked(new_size, layout.align()));
+        let start = ptr as usize;
+
+        let resized = if size < old_size {
+            // The tail can only be given back if a Node fits in it
+            let tail = old_size - size;
+            if tail >= mem::size_of::<Node>() {
+                self.add_free_region(start + size, tail);
+            }
+            tail >= mem::size_of::<Node>()
+        } else if size > old_size {
+            let (end, extra) = (start + old_size, size - old_size);
+            let reaches_heap_end = self.free_region_at(end).map_or(end, Node::end_addr) == self.heap_end;
+            let grown = self.take_front(end, extra)
+                || (reaches_heap_end && self.grow(extra, mem::align_of::<Node>()) && self.take_front(end, extra));
+            if grown {
+                self.dirty_end = self.dirty_end.max(start + size);
+            }
+            grown
+        } else {
+            true
+        };
+
+        if resized {
+            self.counters.record_realloc(layout.size(), new_size);
+        }
+        resized
+    }
+
+    /// Returns the free region starting exactly at `addr`, if any.
+    fn free_region_at(&self, addr: usize) -> Option<&Node> {
+        let mut current = &self.head;
+        while let Some(ref region) = current.next {
+            if region.start_addr() >= addr {
+                return if region.start_addr() == addr { Some(region) } else { None };
+            }
+            current = region;
+        }
+        None
+    }
+
+    /// Removes the first `size` bytes of the free region starting exactly at `addr`.
+    ///
+    /// As in `alloc_from_region`, what remains of the region must either be empty or able to hold a Node.
+    /// Returns whether there was such a region.
+    unsafe fn take_front(&mut self, addr: usize, size: usize) -> bool {
+        let mut current = &mut self.head;
+        while current.next.as_ref().map_or(false, |next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+        let region = match current.next.as_mut() {
+            Some(region) if region.start_addr() == addr && region.size >= size => region,
+            _ => return false,
+        };
+        let excess_size = region.size - size;
+        if excess_size > 0 && excess_size < mem::size_of::<Node>() {
+            return false;
+        }
+
+        // The remainder of the region takes its place in the list, which thus stays sorted
+        let next = region.next.take();
+        if excess_size == 0 {
+            current.next = next;
+        } else {
+            let node_ptr = (addr + size) as *mut Node;
+            node_ptr.write(Node { size: excess_size, next });
+            current.next = Some(&mut *node_ptr);
+            self.dirty_end = self.dirty_end.max(addr + size + mem::size_of::<Node>());
+        }
+        true
+    }
+
+    /// Walks the free list, returning its length, its total size and the size of its largest region.
+    fn free_list_stats(&self) -> (usize, usize, usize) {
+        let mut current = &self.head;
+        let (mut regions, mut bytes, mut largest) = (0, 0, 0);
+        while let Some(ref region) = current.next {
+            regions += 1;
+            bytes += region.size;
+            largest = largest.max(region.size);
+            current = region;
+        }
+        (regions, bytes, largest)
+    }
+}
+
+impl Introspect for LinkedListAlloc {
+    fn stats(&self) -> HeapStats {
+        let (free_regions, free_bytes, largest_free_region) = self.free_list_stats();
+        HeapStats {
+            free_regions: Some(free_regions),
+            free_bytes,
+            largest_free_region: Some(largest_free_region),
+            ..self.counters.stats()
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAlloc> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout).0
+    }
+
+    /// Only the bytes which were handed out or held a node before are zeroed, the rest of the heap being zeroed
+    /// already.
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let (ptr, dirty) = self.lock().allocate(layout);
+        if !ptr.is_null() {
+            ptr::write_bytes(ptr, 0, dirty.min(layout.size()));
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.lock().reallocate(ptr, layout, new_size) {
+            return ptr;
+        }
+        realloc_by_copy(self, ptr, layout, new_size)
+    }
+}
+
+#[test_case]
+fn freed_neighbours_coalesce() {
+    const ARENA_SIZE: usize = 4096;
+    let arena_start = super::test_arena(ARENA_SIZE);
+    let allocator = Locked::new(LinkedListAlloc::new());
+    unsafe { allocator.lock().init(arena_start, ARENA_SIZE) };
+
+    let layouts = [
+        Layout::from_size_align(24, 8).unwrap(),
+        Layout::from_size_align(100, 8).unwrap(),
+        Layout::from_size_align(64, 256).unwrap(), // Leaves some padding in front
+        Layout::from_size_align(300, 16).unwrap(),
+    ];
+    let ptrs = layouts.map(|layout| unsafe { allocator.alloc(layout) });
+    assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+
+    // Out-of-order frees, so that merges with the previous, the next and both neighbours all happen
+    for i in [1, 3, 0, 2] {
+        unsafe { allocator.dealloc(ptrs[i], layouts[i]) };
+    }
+
+    let allocator = allocator.lock();
+    let region = allocator.head.next.as_ref().expect("free list is empty");
+    assert_eq!(region.start_addr(), arena_start);
+    assert_eq!(region.size, ARENA_SIZE);
+    assert!(region.next.is_none());
+}
+
+#[test_case]
+fn realloc_in_place() {
+    const ARENA_SIZE: usize = 4096;
+    let allocator = Locked::new(LinkedListAlloc::new());
+    unsafe { allocator.lock().init(super::test_arena(ARENA_SIZE), ARENA_SIZE) };
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let first = unsafe { allocator.alloc(layout) };
+    let second = unsafe { allocator.alloc(layout) };
+    unsafe { first.write_bytes(0xab, 64) };
+
+    // The first block is followed by an allocated one, so it can only shrink in place
+    let shrunk = unsafe { allocator.realloc(first, layout, 32) };
+    assert_eq!(shrunk, first);
+    let grown = unsafe { allocator.realloc(first, Layout::from_size_align(32, 8).unwrap(), 64) };
+    assert_eq!(grown, first); // Takes back the tail given back above
+    let moved = unsafe { allocator.realloc(first, layout, 128) };
+    assert_ne!(moved, first);
+    assert!(unsafe { core::slice::from_raw_parts(moved, 32) }.iter().all(|&b| b == 0xab)); // Kept since the shrink
+
+    // The moved block is followed by the whole free end of the arena
+    let grown = unsafe { allocator.realloc(moved, Layout::from_size_align(128, 8).unwrap(), 1024) };
+    assert_eq!(grown, moved);
+
+    unsafe {
+        allocator.dealloc(second, layout);
+        allocator.dealloc(grown, Layout::from_size_align(1024, 8).unwrap());
+    }
+    let stats = allocator.stats();
+    assert_eq!(stats.bytes_in_use, 0);
+    assert_eq!(stats.free_regions, Some(1));
+}
+
+#[test_case]
+fn alloc_zeroed_skips_fresh_memory() {
+    const ARENA_SIZE: usize = 4096;
+    let allocator = Locked::new(LinkedListAlloc::new());
+    unsafe { allocator.lock().init(super::test_arena(ARENA_SIZE), ARENA_SIZE) };
+    let layout = Layout::from_size_align(256, 8).unwrap();
+
+    // Only the node of the free region was ever written to
+    let (first, dirty) = unsafe { allocator.lock().allocate(layout) };
+    assert_eq!(dirty, mem::size_of::<Node>());
+    unsafe {
+        first.write_bytes(0xff, layout.size());
+        allocator.dealloc(first, layout);
+    }
+    let (again, dirty) = unsafe { allocator.lock().allocate(layout) };
+    assert_eq!((again, dirty), (first, layout.size()));
+
+    let zeroed = unsafe { allocator.alloc_zeroed(layout) };
+    assert!(unsafe { core::slice::from_raw_parts(zeroed, layout.size()) }.iter().all(|&b| b == 0));
+    unsafe {
+        allocator.dealloc(again, layout);
+        allocator.dealloc(zeroed, layout);
+    }
+    assert_eq!(allocator.stats().free_regions, Some(1));
 }
@@ -1,55 +1,322 @@
 use alloc::alloc::{GlobalAlloc, Layout};
-use linked_list_allocator::LockedHeap;
+use core::fmt;
+use core::ptr;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB
-    },
+    structures::paging::{OffsetPageTable, PageSize, Size2MiB, Size4KiB},
     VirtAddr,
 };
 
-use linked_list::LinkedListAlloc;
-use fixed_size::FixedSizeAlloc;
+use crate::memory::{BuddyFrameAllocator, KernelPaging, RegionKind, VmmError, KERNEL_PAGING};
+use crate::{exit_qemu, hlt_loop, println, serial_println, QemuExitCode};
+use fixed_size::BLOCK_SIZES;
 pub mod bump;
 pub mod linked_list;
 pub mod fixed_size;
+pub mod external;
+pub mod debug;
+
+// The global allocator is picked at compile time with one of the `alloc-*` cargo features
+#[cfg(any(
+    all(feature = "alloc-bump", any(feature = "alloc-linked-list", feature = "alloc-fixed-size", feature = "alloc-external")),
+    all(feature = "alloc-linked-list", any(feature = "alloc-fixed-size", feature = "alloc-external")),
+    all(feature = "alloc-fixed-size", feature = "alloc-external"),
+))]
+compile_error!("only one `alloc-*` feature may be enabled (disable the default one with `--no-default-features`)");
+
+#[cfg(not(any(feature = "alloc-bump", feature = "alloc-linked-list", feature = "alloc-fixed-size", feature = "alloc-external")))]
+compile_error!("one `alloc-*` feature must be enabled to select the global allocator");
+
+#[cfg(feature = "alloc-bump")]
+pub type GlobalAllocator = bump::BumpAlloc;
+#[cfg(feature = "alloc-linked-list")]
+pub type GlobalAllocator = linked_list::LinkedListAlloc;
+#[cfg(feature = "alloc-fixed-size")]
+pub type GlobalAllocator = fixed_size::FixedSizeAlloc;
+#[cfg(feature = "alloc-external")]
+pub type GlobalAllocator = external::ExternalAlloc;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1_024; // 100 KiB
+pub const HEAP_SIZE: usize = 100 * 1_024; // 100 KiB, mapped at init
+pub const HEAP_MAX_SIZE: usize = 16 * 1_024 * 1_024; // 16 MiB, size of the virtual region reserved for the heap
+const HEAP_GROWTH_MIN: usize = 16 * 1_024; // Avoids growing the heap a page at a time
 
+// Bytes from HEAP_START handed to the allocator, past the initial ones being mapped on first access
+static HEAP_COMMITTED: AtomicUsize = AtomicUsize::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+static ALLOC_ERROR_HOOK: spin::Mutex<Option<fn(QemuExitCode) -> !>> = spin::Mutex::new(None);
+
+#[cfg(not(feature = "alloc-debug"))]
 #[global_allocator]
-//pub static ALLOCATOR: Locked<LinkedListAlloc> = Locked::new(LinkedListAlloc::new());
-pub static ALLOCATOR: Locked<FixedSizeAlloc> = Locked::new(FixedSizeAlloc::new());
+pub static ALLOCATOR: Locked<GlobalAllocator> = Locked::new(GlobalAllocator::new());
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE as u64 - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+// Same allocator, with heap corruption checks (dereferences to the `Locked` one)
+#[cfg(feature = "alloc-debug")]
+#[global_allocator]
+pub static ALLOCATOR: debug::DebugAlloc<GlobalAllocator> = debug::DebugAlloc::new(GlobalAllocator::new());
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
-    }
+/// Reserves the heap region, maps its first `HEAP_SIZE` bytes and initializes `ALLOCATOR` with them.
+///
+/// The mapper and frame allocator are then kept in `memory::KERNEL_PAGING`, so that the heap can grow
+/// over the rest of the region on demand, up to the limit set by `set_heap_limit`, with demand-zero pages.
+pub fn init_heap(
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BuddyFrameAllocator,
+) -> Result<(), VmmError> {
+    let mut paging = KernelPaging::new(mapper, frame_allocator);
+    let heap_pages = (HEAP_MAX_SIZE as u64) / Size4KiB::SIZE;
+    let heap = paging.vmm.reserve_at(VirtAddr::new(HEAP_START as u64), heap_pages, RegionKind::Heap)?;
+    paging.map_pages(&heap, 0, (HEAP_SIZE as u64) / Size4KiB::SIZE)?;
+    HEAP_COMMITTED.store(HEAP_SIZE, Ordering::SeqCst);
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
     }
 
+    *KERNEL_PAGING.lock() = Some(paging);
     Ok(())
 }
 
+/// Sets the maximum size the heap may grow to, which cannot exceed `HEAP_MAX_SIZE`.
+/// The heap never shrinks, even under a lower limit.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit, Ordering::SeqCst);
+}
+
+/// Returns the size of the heap, part of which may not be mapped yet.
+pub fn heap_size() -> usize {
+    HEAP_COMMITTED.load(Ordering::SeqCst)
+}
+
+/// Hands at least `min_size` more bytes right after `heap_end`, which must be the current end of
+/// the kernel heap (allocators managing another memory area thus never grow), to the allocator.
+///
+/// The new pages are demand-zero ones (see `memory::demand_zero`): their frames are reserved right away,
+/// but the page fault handler only zeroes and maps them when first accessed, which it can do without the paging
+/// structures, even if they are locked then. The heap grows 2 MiB at a time once its end is aligned on 2 MiB,
+/// so that huge pages map it (see `memory::huge`).
+/// Returns the number of bytes added, or `None` if the heap limit is reached, no frame is left,
+/// or the paging structures are in use. The latter is only tried for, as allocators call this
+/// with their own lock held, and an allocation made while mapping memory must fail rather than deadlock.
+fn grow_heap(heap_end: usize, min_size: usize) -> Option<usize> {
+    let mut paging = KERNEL_PAGING.try_lock()?;
+    let paging = paging.as_mut()?;
+
+    let committed = HEAP_COMMITTED.load(Ordering::SeqCst);
+    if heap_end != HEAP_START + committed {
+        return None;
+    }
+    let limit = HEAP_LIMIT.load(Ordering::SeqCst).min(HEAP_MAX_SIZE);
+    let available = limit.saturating_sub(committed);
+    let mut wanted = align_up(min_size.max(HEAP_GROWTH_MIN), Size4KiB::SIZE as usize);
+    if heap_end % Size2MiB::SIZE as usize == 0 {
+        wanted = align_up(wanted, Size2MiB::SIZE as usize);
+    }
+    let wanted = wanted.min(available);
+    if wanted < min_size {
+        return None;
+    }
+
+    // Whatever was committed before running out of frames is still handed out
+    let heap = paging.vmm.find(VirtAddr::new(HEAP_START as u64))?;
+    let first = (committed as u64) / Size4KiB::SIZE;
+    let pages = paging.commit_pages(&heap, first, (wanted as u64) / Size4KiB::SIZE);
+    if pages == 0 {
+        return None;
+    }
+    let grown = (pages * Size4KiB::SIZE) as usize;
+    HEAP_COMMITTED.fetch_add(grown, Ordering::SeqCst);
+    Some(grown)
+}
+
+/// Allocates from a heap of the `linked_list_allocator` crate, growing it once if it is full,
+/// for the allocators built upon one.
+fn allocate_growing(heap: &mut linked_list_allocator::Heap, layout: Layout) -> *mut u8 {
+    if let Ok(ptr) = heap.allocate_first_fit(layout) {
+        return ptr.as_ptr();
+    }
+    match grow_heap(heap.top() as usize, layout.size() + layout.align()) {
+        Some(grown) => {
+            unsafe { heap.extend(grown) };
+            heap.allocate_first_fit(layout).map_or(null_mut(), |ptr| ptr.as_ptr())
+        }
+        None => null_mut(),
+    }
+}
+
+/// Has `hook` called with `QemuExitCode::OutOfMemory` once the diagnostics of a failed allocation are printed,
+/// instead of the test run failing, for tests running out of memory on purpose. The hook ends the run itself.
+pub fn set_alloc_error_hook(hook: fn(QemuExitCode) -> !) {
+    *ALLOC_ERROR_HOOK.lock() = Some(hook);
+}
+
+/// Called when an allocation fails and the caller cannot handle it (e.g. a `Box` or `Vec` could not be allocated).
+///
+/// Reports the failing layout and the heap occupancy over serial and VGA, instead of the bare panic of the default
+/// handler, and fails the test run right away when testing (unless a hook expects it, see `set_alloc_error_hook`).
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let report = AllocError {
+        layout,
+        stats: ALLOCATOR.stats(),
+        committed: heap_size(),
+        limit: HEAP_LIMIT.load(Ordering::SeqCst),
+    };
+    let hook = ALLOC_ERROR_HOOK.lock().take();
+    let testing = crate::TESTING.load(Ordering::SeqCst);
+    if testing && hook.is_none() {
+        serial_println!("[failed]\n");
+    }
+    serial_println!("{}", report);
+    println!("{}", report);
+
+    if let Some(hook) = hook {
+        hook(QemuExitCode::OutOfMemory);
+    }
+    if testing {
+        exit_qemu(QemuExitCode::OutOfMemory);
+    }
+    hlt_loop();
+}
+
+/// Diagnostics printed by `alloc_error`
+struct AllocError {
+    layout: Layout,
+    stats: HeapStats,
+    committed: usize,
+    limit: usize,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Out of memory: allocation of {} B aligned on {} B failed",
+            self.layout.size(), self.layout.align()
+        )?;
+        writeln!(f, "Heap of {} B, out of at most {} B", self.committed, self.limit)?;
+        writeln!(f, "{}", self.stats)?;
+        match self.stats.fragmentation() {
+            Some(fragmentation) => write!(f, "Fragmentation: {}%", fragmentation),
+            None => write!(f, "Fragmentation: unknown"),
+        }
+    }
+}
+
+/// Usage statistics of a heap allocator, as returned by `Locked::stats`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub bytes_in_use: usize, // As requested by the allocations' layouts
+    pub peak_bytes_in_use: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    pub free_regions: Option<usize>, // Length of the free list, `None` if the allocator does not expose it
+    pub free_bytes: usize,
+    pub largest_free_region: Option<usize>,
+    pub size_classes: [SizeClassStats; BLOCK_SIZES.len()], // Only filled in by `FixedSizeAlloc`
+}
+
+/// Occupancy of one of the `BLOCK_SIZES` classes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    pub in_use: usize,
+    pub free: usize,
+    pub slabs: usize, // 0 for the classes whose blocks are not carved out of slabs
+}
+
+impl HeapStats {
+    /// Number of allocations not freed yet
+    pub fn live_allocations(&self) -> usize {
+        self.allocations - self.deallocations
+    }
+
+    /// Share of the free bytes lying outside of the largest free region, in percent: the higher it is, the more
+    /// an allocation may fail despite enough free memory. `None` if the allocator does not expose its free regions.
+    pub fn fragmentation(&self) -> Option<usize> {
+        let largest_free_region = self.largest_free_region?;
+        if self.free_bytes == 0 {
+            return Some(0);
+        }
+        Some(100 - largest_free_region * 100 / self.free_bytes)
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} B in use (peak {} B), {} allocations, {} deallocations",
+            self.bytes_in_use, self.peak_bytes_in_use, self.allocations, self.deallocations
+        )?;
+        match (self.free_regions, self.largest_free_region) {
+            (Some(regions), Some(largest)) => {
+                write!(f, "{} B free in {} regions, largest {} B", self.free_bytes, regions, largest)?
+            }
+            _ => write!(f, "{} B free, in unknown regions", self.free_bytes)?,
+        }
+        for (size, class) in BLOCK_SIZES.iter().zip(self.size_classes.iter()) {
+            if class.slabs > 0 {
+                write!(f, "\n{} B blocks: {} in use, {} free in {} slabs", size, class.in_use, class.free, class.slabs)?;
+            } else if class.in_use > 0 {
+                write!(f, "\n{} B blocks: {} in use", size, class.in_use)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Allocators able to report their usage
+pub trait Introspect {
+    fn stats(&self) -> HeapStats;
+}
+
+/// Allocation counters kept by each allocator, from which its `HeapStats` are built
+struct Counters {
+    bytes_in_use: usize,
+    peak_bytes_in_use: usize,
+    allocations: usize,
+    deallocations: usize,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            allocations: 0,
+            deallocations: 0,
+        }
+    }
+
+    fn record_alloc(&mut self, size: usize) {
+        self.bytes_in_use += size;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+        self.allocations += 1;
+    }
+
+    fn record_dealloc(&mut self, size: usize) {
+        self.bytes_in_use -= size;
+        self.deallocations += 1;
+    }
+
+    /// An allocation resized in place, counted neither as an allocation nor as a deallocation
+    fn record_realloc(&mut self, old_size: usize, new_size: usize) {
+        self.bytes_in_use = self.bytes_in_use - old_size + new_size;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+    }
+
+    /// Stats holding the counters only, to be completed by the allocator
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            bytes_in_use: self.bytes_in_use,
+            peak_bytes_in_use: self.peak_bytes_in_use,
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+            ..HeapStats::default()
+        }
+    }
+}
 
 /// A wrapper around spin::Mutex to permit trait implementations, in order to bypass immutability implied
 /// by GlobalAlloc trait implementation.
@@ -67,6 +334,48 @@ impl<T> Locked<T> {
     pub fn lock(&self) -> spin::MutexGuard<T> {
         self.inner.lock()
     }
+
+    /// Whether the lock is held, e.g. by the code an interrupt handler interrupted
+    pub fn is_locked(&self) -> bool {
+        self.inner.try_lock().is_none()
+    }
+}
+
+impl<T: Introspect> Locked<T> {
+    /// Returns the current usage statistics of the inner allocator
+    pub fn stats(&self) -> HeapStats {
+        self.lock().stats()
+    }
+}
+
+/// Reallocates the way `GlobalAlloc::realloc` does by default, for allocators which could not resize in place:
+/// allocates a new block, copies the data over and frees the old block.
+unsafe fn realloc_by_copy(allocator: &impl GlobalAlloc, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    let new_ptr = allocator.alloc(new_layout);
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+        allocator.dealloc(ptr, layout);
+    }
+    new_ptr
+}
+
+/// Start of a zeroed area of `size` bytes to build an allocator on in unit tests, where there is no heap.
+///
+/// Tests run one after the other, so they all share the same area, zeroed again each time it is handed out.
+#[cfg(test)]
+fn test_arena(size: usize) -> usize {
+    const ARENA_SIZE: usize = 64 * 1024;
+    #[repr(align(4096))]
+    struct Arena([u8; ARENA_SIZE]);
+    static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+    assert!(size <= ARENA_SIZE);
+    unsafe {
+        let start = ptr::addr_of_mut!(ARENA.0) as *mut u8;
+        start.write_bytes(0, size);
+        start as usize
+    }
 }
 
 /// Align the given address `addr` upwards to alignment `align`.
@@ -1,19 +1,40 @@
-use core::ptr::addr_of;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory::{VmmError, KERNEL_PAGING};
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+        // The TSS is only written to by `init` and `init_ist_stacks`, before and after being loaded
+        let tss_selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(addr_of!(TSS)) });
+        #[cfg(feature = "test-user-mode")]
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+        #[cfg(feature = "test-user-mode")]
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        // A data segment with its present bit clear, for the segment not present exception to be raised
+        #[cfg(test)]
+        let not_present_selector = {
+            use x86_64::structures::gdt::DescriptorFlags;
+            let flags = DescriptorFlags::KERNEL_DATA.bits() & !DescriptorFlags::PRESENT.bits();
+            gdt.append(Descriptor::UserSegment(flags))
+        };
         (
             gdt,
             Selectors {
                 code_selector,
                 tss_selector,
+                #[cfg(feature = "test-user-mode")]
+                user_code_selector,
+                #[cfg(feature = "test-user-mode")]
+                user_data_selector,
+                #[cfg(test)]
+                not_present_selector,
             },
         )
     };
@@ -22,12 +43,30 @@ lazy_static! {
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    #[cfg(feature = "test-user-mode")]
+    user_code_selector: SegmentSelector,
+    #[cfg(feature = "test-user-mode")]
+    user_data_selector: SegmentSelector,
+    #[cfg(test)]
+    not_present_selector: SegmentSelector,
+}
+
+/// Code and data selectors of user mode, with a requested privilege level of 3.
+#[cfg(feature = "test-user-mode")]
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+#[cfg(test)]
+pub(crate) fn not_present_selector() -> SegmentSelector {
+    GDT.1.not_present_selector
 }
 
 pub fn init() {
     use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
 
+    set_ist_stacks(core::array::from_fn(boot_ist_stack));
     GDT.0.load();
     // Suppose the selectors are valid
     unsafe {
@@ -37,25 +76,90 @@ pub fn init() {
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1; // So that page faults on lazily committed stacks can be handled
+const PAGE_FAULT_NESTING: usize = 2; // Page faults which may be handled at once, each on its own stack
+const IST_STACKS: usize = 1 + PAGE_FAULT_NESTING; // The double fault one, then one per page fault nesting level
+const IST_STACK_PAGES: u64 = 5;
 
 // The Interrupt Stack in the case of a Double Fault.
 // Double faults indeed need a separate stack, switched from the user task one, because:
 // In the case of user-stack overflow, the double fault immediately propagates to a triple fault (fatal),
 // as the double fault's ISF will also reside in the guard page.
 // Thus, all double faults are handled and no triple fault can occur
-lazy_static! {
-    /// !!!CAREFUL!!!: The Interrupt Stack Frame should not be host to stack-intensive tasks
-    /// as NO guard page is present
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) }); // Addr_of
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
-        tss
-    };
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+// Tops of the page fault stacks, the IST entry pointing at the one of the next nesting level (see `PageFaultNesting`)
+static mut PAGE_FAULT_STACKS: [VirtAddr; PAGE_FAULT_NESTING] = [VirtAddr::zero(); PAGE_FAULT_NESTING];
+static PAGE_FAULT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the top of the `index`th static interrupt stack, used until `init_ist_stacks` is called,
+/// as memory cannot be mapped yet when booting.
+///
+/// !!!CAREFUL!!!: The Interrupt Stack Frame should not be host to stack-intensive tasks
+/// as NO guard page is present
+fn boot_ist_stack(index: usize) -> VirtAddr {
+    const STACK_SIZE: usize = 4096 * 5;
+    static mut STACKS: [[u8; STACK_SIZE]; IST_STACKS] = [[0; STACK_SIZE]; IST_STACKS];
+
+    let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACKS[index]) }); // Addr_of
+    let stack_end = stack_start + STACK_SIZE as u64;
+    stack_end
+}
+
+/// Replaces the interrupt stacks with guard-paged kernel stacks, once `memory::KERNEL_PAGING` is set up.
+pub fn init_ist_stacks() -> Result<(), VmmError> {
+    let mut paging = KERNEL_PAGING.lock();
+    let paging = paging.as_mut().expect("kernel paging not initialized");
+    let mut stacks = [VirtAddr::zero(); IST_STACKS];
+    for top in stacks.iter_mut() {
+        // Fully mapped, as a fault on an interrupt stack could not be handled
+        *top = paging.allocate_stack(IST_STACK_PAGES)?.top();
+    }
+    set_ist_stacks(stacks);
+    Ok(()) // The stacks are used until shutdown, so they are never freed
+}
+
+/// Points the double fault IST entry at the first of `stacks`, and the page fault one at the second,
+/// the others being kept for nested page faults.
+fn set_ist_stacks(stacks: [VirtAddr; IST_STACKS]) {
+    // The CPU reads the stack pointer from the TSS on each interrupt, so the loaded TSS is updated in place
+    unsafe {
+        let tss = &mut *addr_of_mut!(TSS);
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stacks[0];
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = stacks[1];
+        (*addr_of_mut!(PAGE_FAULT_STACKS)).copy_from_slice(&stacks[1..]);
+    }
+}
+
+/// Sets the stack the CPU switches to when an interrupt or exception comes from user mode,
+/// unless it has an interrupt stack of its own.
+#[cfg(feature = "test-user-mode")]
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (*addr_of_mut!(TSS)).privilege_stack_table[0] = top };
+}
+
+/// Held by the page fault handler while it runs, so that a page fault happening meanwhile (e.g. on a lazy stack
+/// page the handler touches) pushes its frame onto the stack of the next nesting level, instead of over the frame
+/// being handled, as the IST entry would otherwise have it.
+///
+/// Past `PAGE_FAULT_NESTING` levels, the entry points at the top of the address space, which nothing maps,
+/// so that the CPU fails to push the frame and double faults instead.
+pub struct PageFaultNesting(());
+
+impl PageFaultNesting {
+    /// Only to be called by the page fault handler, which runs with interrupts disabled.
+    pub fn enter() -> Self {
+        let depth = PAGE_FAULT_DEPTH.fetch_add(1, Ordering::SeqCst) + 1;
+        let next = unsafe { (*addr_of!(PAGE_FAULT_STACKS)).get(depth).copied() }.unwrap_or(VirtAddr::zero());
+        unsafe { (*addr_of_mut!(TSS)).interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = next };
+        PageFaultNesting(())
+    }
+}
+
+impl Drop for PageFaultNesting {
+    fn drop(&mut self) {
+        let depth = PAGE_FAULT_DEPTH.fetch_sub(1, Ordering::SeqCst) - 1;
+        let top = unsafe { (*addr_of!(PAGE_FAULT_STACKS))[depth] };
+        unsafe { (*addr_of_mut!(TSS)).interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = top };
+    }
 }
@@ -0,0 +1,220 @@
+// Local APIC and IO-APIC, which replace the 8259 PICs once found (see `init`), the PICs being kept otherwise.
+// The local APIC of the CPU delivers the interrupts and is told their end, while the IO-APIC routes the interrupt
+// lines of the devices to it, as described by the ACPI tables (see `madt`). ISA lines keep the vectors the PICs gave
+// them, so that the handlers registered through `irq` carry on, and the PICs are then masked altogether.
+// The registers of both are reached through MMIO regions of the kernel VMM.
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{VmmError, KERNEL_PAGING};
+
+use super::irq::{self, PIC_LINES};
+use super::madt::{self, Route};
+use super::PIC_1_OFFSET;
+
+/// Vector of the spurious interrupts of the local APIC, which must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// Vector of the local APIC timer, the first one after the ISA lines
+pub const TIMER_VECTOR: u8 = irq::FIRST_VECTOR + PIC_LINES;
+
+const APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS: u64 = 0x000f_ffff_ffff_f000;
+
+// Registers of the local APIC, as offsets from its base
+const ID: u64 = 0x20;
+const TASK_PRIORITY: u64 = 0x80;
+const END_OF_INTERRUPT: u64 = 0xb0;
+const SPURIOUS_INTERRUPT: u64 = 0xf0;
+const LVT_TIMER: u64 = 0x320;
+const TIMER_INITIAL_COUNT: u64 = 0x380;
+const TIMER_CURRENT_COUNT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const MASKED: u32 = 1 << 16; // Of local vector table and redirection entries
+const PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// Ratio between the bus clock and the rate at which the local APIC timer counts down
+pub const TIMER_DIVISOR: u64 = 16;
+
+// Registers of the IO-APIC, which are selected, then read or written through a window
+const IO_REGISTER_SELECT: u64 = 0x00;
+const IO_WINDOW: u64 = 0x10;
+const IO_VERSION: u32 = 0x01;
+const IO_REDIRECTION: u32 = 0x10; // Two registers per input, the destination being in the second one
+const ACTIVE_LOW: u32 = 1 << 13;
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+
+#[derive(Debug)]
+pub enum ApicError {
+    NotSupported, // By the CPU
+    NoIoApic, // No MADT listing one was found
+    NoPaging, // The kernel paging structures are not set up yet (see `allocator::init_heap`)
+    Vmm(VmmError),
+}
+
+// Base of the local APIC registers, 0 while the PICs are in use: read without a lock, as interrupts end through it
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+static IO_APIC: Mutex<Option<IoApic>> = Mutex::new(None);
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    inputs: u32,
+    routes: [Route; PIC_LINES as usize],
+    destination: u32, // Id of the local APIC interrupts are delivered to
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        unsafe {
+            (self.base + IO_REGISTER_SELECT).as_mut_ptr::<u32>().write_volatile(register);
+            (self.base + IO_WINDOW).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            (self.base + IO_REGISTER_SELECT).as_mut_ptr::<u32>().write_volatile(register);
+            (self.base + IO_WINDOW).as_mut_ptr::<u32>().write_volatile(value);
+        }
+    }
+
+    /// Routes ISA line `line` to its vector, masked or not, provided that it is wired to this IO-APIC.
+    fn set_masked(&mut self, line: u8, masked: bool) {
+        let route = self.routes[usize::from(line)];
+        let Some(input) = route.gsi.checked_sub(self.gsi_base).filter(|&input| input < self.inputs) else {
+            return;
+        };
+        let mut entry = u32::from(PIC_1_OFFSET + line);
+        if route.active_low {
+            entry |= ACTIVE_LOW;
+        }
+        if route.level_triggered {
+            entry |= LEVEL_TRIGGERED;
+        }
+        if masked {
+            entry |= MASKED;
+        }
+        self.write(IO_REDIRECTION + 2 * input + 1, self.destination << 24);
+        self.write(IO_REDIRECTION + 2 * input, entry);
+    }
+}
+
+fn local_read(base: u64, register: u64) -> u32 {
+    unsafe { ((base + register) as *const u32).read_volatile() }
+}
+
+fn local_write(base: u64, register: u64, value: u32) {
+    unsafe { ((base + register) as *mut u32).write_volatile(value) };
+}
+
+/// Whether the CPU has a local APIC, as reported by CPUID leaf 1
+pub fn supported() -> bool {
+    __cpuid(1).edx & (1 << 9) != 0
+}
+
+/// Whether interrupts go through the APICs rather than the PICs
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::SeqCst) != 0
+}
+
+/// Tells the local APIC that the interrupt being handled ended.
+pub(super) fn end_of_interrupt() {
+    let base = LOCAL_APIC.load(Ordering::SeqCst);
+    if base != 0 {
+        local_write(base, END_OF_INTERRUPT, 0);
+    }
+}
+
+/// Masks or unmasks the IO-APIC input ISA line `line` is wired to.
+pub(super) fn set_masked(line: u8, masked: bool) {
+    if let Some(io_apic) = IO_APIC.lock().as_mut() {
+        io_apic.set_masked(line, masked);
+    }
+}
+
+/// Starts the local APIC timer from `count`, raising `TIMER_VECTOR` each time it reaches 0 if `periodic`,
+/// or counting down once and silently otherwise (e.g. to calibrate it). A count of 0 stops it.
+pub fn start_timer(count: u32, periodic: bool) {
+    let base = LOCAL_APIC.load(Ordering::SeqCst);
+    if base == 0 {
+        return;
+    }
+    let mode = if periodic { PERIODIC } else { MASKED };
+    local_write(base, TIMER_DIVIDE, DIVIDE_BY_16);
+    local_write(base, LVT_TIMER, mode | u32::from(TIMER_VECTOR));
+    local_write(base, TIMER_INITIAL_COUNT, count);
+}
+
+/// Returns what is left of the count of the local APIC timer, 0 once it is reached or if the APIC is not enabled.
+pub fn timer_count() -> u32 {
+    match LOCAL_APIC.load(Ordering::SeqCst) {
+        0 => 0,
+        base => local_read(base, TIMER_CURRENT_COUNT),
+    }
+}
+
+/// Enables the local APIC and routes the ISA lines which have handlers through the IO-APIC, masking the PICs.
+///
+/// Needs the kernel paging structures, to map the registers of both. The PICs are kept if no APIC is found,
+/// which the returned error tells.
+pub fn init() -> Result<(), ApicError> {
+    if is_enabled() {
+        return Ok(());
+    }
+    if !supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = madt::find().ok_or(ApicError::NoIoApic)?;
+
+    let mut base_msr = Msr::new(APIC_BASE_MSR);
+    let local_phys = PhysAddr::new(unsafe { base_msr.read() } & APIC_BASE_ADDRESS);
+    let (local, io) = {
+        let mut paging = KERNEL_PAGING.lock();
+        let paging = paging.as_mut().ok_or(ApicError::NoPaging)?;
+        let local = paging.map_mmio(local_phys, 4096).map_err(ApicError::Vmm)?;
+        let io = match paging.map_mmio(madt.io_apic, 0x20) {
+            Ok(io) => io,
+            Err(err) => {
+                paging.free_region(local).map_err(ApicError::Vmm)?;
+                return Err(ApicError::Vmm(err));
+            }
+        };
+        (local.start.as_u64(), io.start + madt.io_apic.as_u64() % 4096)
+    };
+
+    unsafe { base_msr.write(base_msr.read() | APIC_BASE_ENABLE) };
+    local_write(local, TASK_PRIORITY, 0); // Accept every vector
+    local_write(local, LVT_TIMER, MASKED);
+    local_write(local, SPURIOUS_INTERRUPT, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+
+    let mut io_apic = IoApic {
+        base: io,
+        gsi_base: madt.gsi_base,
+        inputs: 0,
+        routes: madt.isa_routes,
+        destination: local_read(local, ID) >> 24,
+    };
+    io_apic.inputs = ((io_apic.read(IO_VERSION) >> 16) & 0xff) + 1;
+    for input in 0..io_apic.inputs {
+        io_apic.write(IO_REDIRECTION + 2 * input, MASKED);
+    }
+
+    irq::switch_to_apic(|| {
+        *IO_APIC.lock() = Some(io_apic);
+        LOCAL_APIC.store(local, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+/// Spurious interrupts are not acknowledged, as they are not in service.
+pub(super) extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
@@ -0,0 +1,392 @@
+// Handlers for the CPU exceptions which `interrupts` does not handle on its own (breakpoints, double and page faults).
+// They are all fatal: each one panics with the name of the exception, its decoded error code, the general-purpose
+// and control registers and the interrupt stack frame, instead of escalating to a double fault as an empty IDT
+// entry would. The x86-interrupt calling convention does not expose the registers of the interrupted code, so each
+// vector has an entry stub of its own instead, which pushes them along with the vector and calls `exception`.
+//
+// Tests may expect an exception (see `raises!`), in which case the handler resumes the execution at a given address
+// instead of panicking. Alignment checks only happen in user mode, so they are raised by a user mode stub in
+// `tests/alignment_check.rs` (built with the `test-user-mode` feature), which checks the panic instead.
+// The others left untested cannot be raised at all: invalid TSS ones only happen on hardware task switches,
+// which long mode does not have, machine checks on hardware errors, and control protection, VMM communication
+// and security exceptions need hardware features QEMU does not provide (CET, SEV).
+
+use core::arch::global_asm;
+use core::fmt;
+#[cfg(test)]
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrameValue, SelectorErrorCode};
+use x86_64::VirtAddr;
+
+#[cfg(test)]
+const NO_VECTOR: u8 = u8::MAX;
+
+// Vector of the exception a test expects, where to resume once it happened, and which one did with its error code
+#[cfg(test)]
+static EXPECTED: AtomicU8 = AtomicU8::new(NO_VECTOR);
+#[cfg(test)]
+static RESUME: AtomicU64 = AtomicU64::new(0);
+#[cfg(test)]
+static CAUGHT: AtomicU8 = AtomicU8::new(NO_VECTOR);
+#[cfg(test)]
+static CAUGHT_ERROR_CODE: AtomicU64 = AtomicU64::new(0);
+
+/// Installs the handlers of every exception vector but the breakpoint, double fault and page fault ones.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    let stub = |stub: unsafe extern "C" fn()| VirtAddr::new(stub as usize as u64);
+    // The stubs save every register and end with `iretq`
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(burritos_divide_error));
+        idt.debug.set_handler_addr(stub(burritos_debug));
+        idt.non_maskable_interrupt.set_handler_addr(stub(burritos_non_maskable_interrupt));
+        idt.overflow.set_handler_addr(stub(burritos_overflow));
+        idt.bound_range_exceeded.set_handler_addr(stub(burritos_bound_range_exceeded));
+        idt.invalid_opcode.set_handler_addr(stub(burritos_invalid_opcode));
+        idt.device_not_available.set_handler_addr(stub(burritos_device_not_available));
+        idt.invalid_tss.set_handler_addr(stub(burritos_invalid_tss));
+        idt.segment_not_present.set_handler_addr(stub(burritos_segment_not_present));
+        idt.stack_segment_fault.set_handler_addr(stub(burritos_stack_segment_fault));
+        idt.general_protection_fault.set_handler_addr(stub(burritos_general_protection_fault));
+        idt.x87_floating_point.set_handler_addr(stub(burritos_x87_floating_point));
+        idt.alignment_check.set_handler_addr(stub(burritos_alignment_check));
+        idt.machine_check.set_handler_addr(stub(burritos_machine_check));
+        idt.simd_floating_point.set_handler_addr(stub(burritos_simd_floating_point));
+        idt.virtualization.set_handler_addr(stub(burritos_virtualization));
+        idt.cp_protection_exception.set_handler_addr(stub(burritos_control_protection));
+        idt.hv_injection_exception.set_handler_addr(stub(burritos_hv_injection));
+        idt.vmm_communication_exception.set_handler_addr(stub(burritos_vmm_communication));
+        idt.security_exception.set_handler_addr(stub(burritos_security_exception));
+    }
+}
+
+/// Error code pushed by an exception, decoded according to its vector
+#[derive(Debug, Clone, Copy)]
+enum ErrorCode {
+    None,
+    Selector(u64), // Descriptor which caused the exception, if any
+    ControlProtection(u64),
+    Raw(u64),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::None => write!(f, "none"),
+            ErrorCode::Selector(0) => write!(f, "0 (not caused by a segment selector)"),
+            ErrorCode::Selector(code) => {
+                let selector = SelectorErrorCode::new_truncate(*code);
+                write!(
+                    f,
+                    "{:#x}: {:?} entry {}{}",
+                    code,
+                    selector.descriptor_table(),
+                    selector.index(),
+                    if selector.external() { ", external event" } else { "" }
+                )
+            }
+            ErrorCode::ControlProtection(code) => {
+                let cause = match code & 0x7fff {
+                    1 => "near RET",
+                    2 => "far RET or IRET",
+                    3 => "missing ENDBRANCH",
+                    4 => "RSTORSSP",
+                    5 => "SETSSBSY",
+                    _ => "unknown",
+                };
+                write!(f, "{:#x} ({})", code, cause)
+            }
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+/// Name of the exception of `vector`, and how its error code is decoded
+fn describe(vector: u8) -> (&'static str, fn(u64) -> ErrorCode) {
+    let none = |_| ErrorCode::None;
+    match vector {
+        0 => ("DIVIDE ERROR", none),
+        1 => ("DEBUG", none),
+        2 => ("NON-MASKABLE INTERRUPT", none),
+        4 => ("OVERFLOW", none),
+        5 => ("BOUND RANGE EXCEEDED", none),
+        6 => ("INVALID OPCODE", none),
+        7 => ("DEVICE NOT AVAILABLE", none),
+        10 => ("INVALID TSS", ErrorCode::Selector),
+        11 => ("SEGMENT NOT PRESENT", ErrorCode::Selector),
+        12 => ("STACK SEGMENT FAULT", ErrorCode::Selector),
+        13 => ("GENERAL PROTECTION FAULT", ErrorCode::Selector),
+        16 => ("X87 FLOATING POINT", none),
+        17 => ("ALIGNMENT CHECK", ErrorCode::Raw),
+        18 => ("MACHINE CHECK", none),
+        19 => ("SIMD FLOATING POINT", none),
+        20 => ("VIRTUALIZATION", none),
+        21 => ("CONTROL PROTECTION", ErrorCode::ControlProtection),
+        28 => ("HYPERVISOR INJECTION", none),
+        29 => ("VMM COMMUNICATION", ErrorCode::Raw),
+        30 => ("SECURITY EXCEPTION", ErrorCode::Raw),
+        _ => ("UNKNOWN", ErrorCode::Raw),
+    }
+}
+
+/// General-purpose registers of the interrupted code, as pushed by `burritos_exception_common`
+/// (RSP is the one of the stack frame)
+#[repr(C)]
+struct Registers {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x} RDX: {:#018x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(f, "RSI: {:#018x} RDI: {:#018x} RBP: {:#018x}", self.rsi, self.rdi, self.rbp)?;
+        writeln!(f, "R8:  {:#018x} R9:  {:#018x} R10: {:#018x} R11: {:#018x}", self.r8, self.r9, self.r10, self.r11)?;
+        write!(f, "R12: {:#018x} R13: {:#018x} R14: {:#018x} R15: {:#018x}", self.r12, self.r13, self.r14, self.r15)
+    }
+}
+
+/// Stack of an exception handler when `exception` is called: the registers, then what the entry stub pushed,
+/// then what the CPU did
+#[repr(C)]
+struct Context {
+    registers: Registers,
+    vector: u64,
+    error_code: u64, // 0 for the exceptions without one
+    frame: InterruptStackFrameValue,
+}
+
+/// Control registers, printed along with the stack frame
+struct ControlRegisters;
+
+impl fmt::Display for ControlRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (level_4_frame, cr3_flags) = Cr3::read();
+        writeln!(f, "CR0: {:?}", Cr0::read())?;
+        writeln!(f, "CR2: {:?}", Cr2::read())?;
+        writeln!(f, "CR3: {:#x} {:?}", level_4_frame.start_address().as_u64(), cr3_flags)?;
+        write!(f, "CR4: {:?}", Cr4::read())
+    }
+}
+
+/// Resumes a test which expected this exception, or panics with a report.
+///
+/// Called by the entry stubs, which restore the registers from `context` once it returns.
+extern "C" fn exception(context: &mut Context) {
+    let vector = context.vector as u8;
+    let (name, decode) = describe(vector);
+    #[cfg(test)]
+    if EXPECTED.compare_exchange(vector, NO_VECTOR, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+        CAUGHT.store(vector, Ordering::SeqCst);
+        CAUGHT_ERROR_CODE.store(context.error_code, Ordering::SeqCst);
+        context.frame.instruction_pointer = VirtAddr::new(RESUME.load(Ordering::SeqCst));
+        return;
+    }
+    panic!(
+        "EXCEPTION: {} (vector {})\nError Code: {}\n{}\n{}\n{:#?}",
+        name, vector, decode(context.error_code), context.registers, ControlRegisters, context.frame
+    );
+}
+
+// Pushes the registers over what the stub pushed, calls `exception` with a stack aligned on 16 bytes (as the CPU
+// aligns it before pushing the frame, 22 quadwords being pushed in all), then pops them back and returns
+global_asm!(
+    ".global burritos_exception_common",
+    "burritos_exception_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call {exception}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "add rsp, 16", // The vector and error code
+    "iretq",
+    exception = sym exception,
+);
+
+// Entry stubs of the exceptions without an error code, which push a 0 in its place, and of those with one
+macro_rules! stubs {
+    ($($stub:ident: $vector:literal;)*) => {
+        global_asm!($(
+            concat!(".global ", stringify!($stub)),
+            concat!(stringify!($stub), ":"),
+            "push 0",
+            concat!("push ", $vector),
+            "jmp burritos_exception_common",
+        )*);
+        extern "C" {
+            $(fn $stub();)*
+        }
+    };
+    ($($stub:ident: $vector:literal, error code;)*) => {
+        global_asm!($(
+            concat!(".global ", stringify!($stub)),
+            concat!(stringify!($stub), ":"),
+            concat!("push ", $vector),
+            "jmp burritos_exception_common",
+        )*);
+        extern "C" {
+            $(fn $stub();)*
+        }
+    };
+}
+
+stubs! {
+    burritos_divide_error: 0;
+    burritos_debug: 1;
+    burritos_non_maskable_interrupt: 2;
+    burritos_overflow: 4;
+    burritos_bound_range_exceeded: 5;
+    burritos_invalid_opcode: 6;
+    burritos_device_not_available: 7;
+    burritos_x87_floating_point: 16;
+    burritos_machine_check: 18;
+    burritos_simd_floating_point: 19;
+    burritos_virtualization: 20;
+    burritos_hv_injection: 28;
+}
+
+stubs! {
+    burritos_invalid_tss: 10, error code;
+    burritos_segment_not_present: 11, error code;
+    burritos_stack_segment_fault: 12, error code;
+    burritos_general_protection_fault: 13, error code;
+    burritos_alignment_check: 17, error code;
+    burritos_control_protection: 21, error code;
+    burritos_vmm_communication: 29, error code;
+    burritos_security_exception: 30, error code;
+}
+
+/// Makes the handler of `vector` resume at the address in `RESUME` rather than panic, for a test to raise it.
+#[cfg(test)]
+fn expect(vector: u8) {
+    CAUGHT.store(NO_VECTOR, Ordering::SeqCst);
+    EXPECTED.store(vector, Ordering::SeqCst);
+}
+
+/// Returns the error code of the exception raised since `expect`, if it was.
+#[cfg(test)]
+fn caught(vector: u8) -> Option<u64> {
+    EXPECTED.store(NO_VECTOR, Ordering::SeqCst);
+    (CAUGHT.load(Ordering::SeqCst) == vector).then(|| CAUGHT_ERROR_CODE.load(Ordering::SeqCst))
+}
+
+/// Runs the given instructions, which must raise exception `vector`, and resumes right after them.
+///
+/// Returns the error code of the exception, or `None` if it was not raised.
+#[cfg(test)]
+macro_rules! raises {
+    ($vector:literal, [$($instruction:literal),+] $(, $($operands:tt)+)?) => {{
+        expect($vector);
+        unsafe {
+            core::arch::asm!(
+                "lea {resume}, [rip + 2f]",
+                "mov [{slot}], {resume}",
+                $($instruction,)+
+                "2:",
+                slot = in(reg) RESUME.as_ptr(),
+                resume = out(reg) _,
+                $($($operands)+)?
+            );
+        }
+        caught($vector)
+    }};
+}
+
+#[test_case]
+fn divide_error_is_caught() {
+    let caught = raises!(0, ["div rcx"], in("rcx") 0u64, inout("rax") 1u64 => _, inout("rdx") 0u64 => _);
+    assert_eq!(caught, Some(0));
+}
+
+#[test_case]
+fn invalid_opcode_is_caught() {
+    assert_eq!(raises!(6, ["ud2"]), Some(0));
+}
+
+#[test_case]
+fn general_protection_fault_decodes_the_selector() {
+    // Far beyond the end of the GDT
+    let caught = raises!(13, ["mov ds, {selector:e}"], selector = in(reg) 0x1230u32);
+    let code = SelectorErrorCode::new_truncate(caught.expect("fault not caught"));
+    assert_eq!(code.index(), 0x1230 >> 3);
+    assert_eq!(code.descriptor_table(), x86_64::structures::idt::DescriptorTable::Gdt);
+
+    // Non-canonical addresses fault without a selector
+    let addr = 0x8000_0000_0000_0000u64;
+    let caught = raises!(13, ["mov {value}, [{addr}]"], addr = in(reg) addr, value = out(reg) _);
+    assert_eq!(caught, Some(0));
+}
+
+#[test_case]
+fn segment_not_present_decodes_the_selector() {
+    // The fault happens before the segment register is loaded, which keeps its previous selector
+    let selector = crate::gdt::not_present_selector();
+    let caught = raises!(11, ["mov ds, {selector:e}"], selector = in(reg) u32::from(selector.0));
+    let code = SelectorErrorCode::new_truncate(caught.expect("fault not caught"));
+    assert_eq!(code.index(), u64::from(selector.index()));
+    assert_eq!(code.descriptor_table(), x86_64::structures::idt::DescriptorTable::Gdt);
+}
+
+#[test_case]
+fn stack_segment_fault_is_caught() {
+    // Non-canonical addresses fault as a stack segment fault when accessed through the stack segment
+    let addr = 0x8000_0000_0000_0000u64;
+    let caught = raises!(12, ["mov {value}, ss:[{addr}]"], addr = in(reg) addr, value = out(reg) _);
+    assert_eq!(caught, Some(0));
+}
+
+// Raised with software interrupts, as they have no error code and cannot be raised otherwise from kernel mode
+#[test_case]
+fn software_raised_exceptions_are_caught() {
+    assert!(raises!(1, ["int 1"]).is_some());
+    assert!(raises!(2, ["int 2"]).is_some());
+    assert!(raises!(4, ["int 4"]).is_some());
+    assert!(raises!(5, ["int 5"]).is_some());
+    assert!(raises!(7, ["int 7"]).is_some());
+    assert!(raises!(16, ["int 16"]).is_some());
+    assert!(raises!(19, ["int 19"]).is_some());
+    assert!(raises!(20, ["int 20"]).is_some());
+    assert!(raises!(28, ["int 28"]).is_some());
+}
@@ -0,0 +1,211 @@
+// Runtime registration of interrupt handlers, so that drivers can claim IRQ lines or vectors.
+// Every vector from `FIRST_VECTOR` on gets a stub in the IDT, which calls the handlers registered for it and then
+// signals the end of the interrupt, so handlers never do it themselves. A vector can be shared by up to `MAX_SHARED`
+// handlers, which are then called in turn. Lines of the PICs are masked while no handler is registered for them,
+// as are their IO-APIC inputs once the APICs replace the PICs (see `apic`).
+// The table is fixed-size, as handlers may be registered before the heap is set up.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use super::{apic, PICS, PIC_1_OFFSET};
+use crate::thread;
+
+/// First vector handlers can be registered for, the vectors below being the CPU exceptions
+pub const FIRST_VECTOR: u8 = PIC_1_OFFSET;
+/// Number of vectors handlers can be registered for: the 16 PIC lines, and as many vectors for other sources
+pub const VECTORS: usize = 32;
+pub const PIC_LINES: u8 = 16;
+const MAX_SHARED: usize = 4;
+const CASCADE_LINE: u8 = 2; // Where the secondary PIC is chained to the primary one
+
+/// What an interrupt calls
+#[derive(Clone, Copy)]
+pub enum Handler {
+    /// A function, called with the given context
+    Fn(fn(usize), usize),
+    Closure(&'static (dyn Fn() + Sync)),
+}
+
+impl Handler {
+    fn call(&self) {
+        match self {
+            Handler::Fn(function, context) => function(*context),
+            Handler::Closure(closure) => closure(),
+        }
+    }
+}
+
+/// Identifies a registered handler, to unregister it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidVector,
+    TooManyHandlers, // The vector is shared by `MAX_SHARED` handlers already
+    NotRegistered,
+}
+
+#[derive(Clone, Copy)]
+struct Registration {
+    handler: Handler,
+    id: u64,
+}
+
+// Written with interrupts disabled, so that an interrupt never waits for the code it interrupted
+static HANDLERS: RwLock<[[Option<Registration>; MAX_SHARED]; VECTORS]> = RwLock::new([[None; MAX_SHARED]; VECTORS]);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Attaches a handler to an interrupt vector, sharing it with the handlers already attached.
+pub fn register(vector: u8, handler: Handler) -> Result<HandlerId, IrqError> {
+    let index = vector.checked_sub(FIRST_VECTOR).map(usize::from).filter(|&index| index < VECTORS);
+    let index = index.ok_or(IrqError::InvalidVector)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let slot = handlers[index].iter_mut().find(|slot| slot.is_none()).ok_or(IrqError::TooManyHandlers)?;
+        *slot = Some(Registration { handler, id });
+        set_masked(vector, false);
+        Ok(HandlerId { vector, id })
+    })
+}
+
+/// Attaches a handler to a line of the PICs.
+pub fn register_irq(line: u8, handler: Handler) -> Result<HandlerId, IrqError> {
+    if line >= PIC_LINES {
+        return Err(IrqError::InvalidVector);
+    }
+    register(PIC_1_OFFSET + line, handler)
+}
+
+/// Detaches a handler, masking its PIC line if it was the last one attached to it.
+pub fn unregister(handler: HandlerId) -> Result<(), IrqError> {
+    let index = usize::from(handler.vector - FIRST_VECTOR);
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let slot = handlers[index]
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|registration| registration.id == handler.id))
+            .ok_or(IrqError::NotRegistered)?;
+        *slot = None;
+        if handlers[index].iter().all(Option::is_none) {
+            set_masked(handler.vector, true);
+        }
+        Ok(())
+    })
+}
+
+/// Masks or unmasks the PIC line of `vector`, if it is one.
+fn set_masked(vector: u8, masked: bool) {
+    let Some(line) = vector.checked_sub(PIC_1_OFFSET).filter(|&line| line < PIC_LINES && line != CASCADE_LINE) else {
+        return;
+    };
+    if apic::is_enabled() {
+        return apic::set_masked(line, masked);
+    }
+    let mut pics = PICS.lock();
+    let mut masks = unsafe { pics.read_masks() };
+    let (mask, bit) = (&mut masks[usize::from(line / 8)], 1 << (line % 8));
+    if masked {
+        *mask |= bit;
+    } else {
+        *mask &= !bit;
+    }
+    unsafe { pics.write_masks(masks[0], masks[1]) };
+}
+
+/// Masks every PIC line but the cascade one, until handlers are registered for them.
+///
+/// Must be called once the PICs are initialized.
+pub(super) fn mask_pic_lines() {
+    without_interrupts(|| unsafe { PICS.lock().write_masks(!(1 << CASCADE_LINE), 0xff) });
+}
+
+/// Masks the PICs for good once `enable` set up the APICs, and unmasks the lines which have handlers there.
+pub(super) fn switch_to_apic(enable: impl FnOnce()) {
+    without_interrupts(|| {
+        let handlers = HANDLERS.read();
+        unsafe { PICS.lock().write_masks(0xff, 0xff) };
+        enable();
+        for line in (0..PIC_LINES).filter(|&line| handlers[usize::from(line)].iter().any(Option::is_some)) {
+            set_masked(PIC_1_OFFSET + line, false);
+        }
+    });
+}
+
+/// Calls the handlers of `vector`, then signals the end of the interrupt.
+fn dispatch(vector: u8) {
+    // Copied, so that handlers may register or unregister others
+    let handlers = HANDLERS.read()[usize::from(vector - FIRST_VECTOR)];
+    for registration in handlers.iter().flatten() {
+        registration.handler.call();
+    }
+    end_of_interrupt(vector);
+    thread::preempt(); // After the end of interrupt, as this thread may not be switched back to for a while
+}
+
+fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        return apic::end_of_interrupt();
+    }
+    let mut pics = PICS.lock();
+    if pics.handles_interrupt(vector) {
+        unsafe { pics.notify_end_of_interrupt(vector) };
+    }
+}
+
+extern "x86-interrupt" fn stub<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(VECTOR);
+}
+
+macro_rules! install_stubs {
+    ($idt:ident, $($vector:literal)*) => {
+        $($idt[$vector].set_handler_fn(stub::<$vector>);)*
+    };
+}
+
+/// Points every vector handlers can be registered for to its stub.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    install_stubs!(idt,
+        32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47
+        48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+    );
+}
+
+#[test_case]
+fn shared_handlers_are_called_until_unregistered() {
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+    static CLOSURE: fn() = || {
+        CALLS.fetch_add(100, Ordering::SeqCst);
+    };
+    fn count(context: usize) {
+        CALLS.fetch_add(context as u64, Ordering::SeqCst);
+    }
+
+    // Vector 48 is no PIC line, so it can be raised by software without any end of interrupt
+    let first = register(48, Handler::Fn(count, 1)).unwrap();
+    let second = register(48, Handler::Closure(&CLOSURE)).unwrap();
+    unsafe { core::arch::asm!("int 48") };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 101);
+
+    unregister(second).unwrap();
+    assert_eq!(unregister(second), Err(IrqError::NotRegistered));
+    unsafe { core::arch::asm!("int 48") };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 102);
+    unregister(first).unwrap();
+}
+
+#[test_case]
+fn vectors_out_of_range_are_refused() {
+    fn nothing(_: usize) {}
+    assert!(register(14, Handler::Fn(nothing, 0)).is_err()); // Page fault
+    assert!(register(FIRST_VECTOR + VECTORS as u8, Handler::Fn(nothing, 0)).is_err());
+    assert!(register_irq(PIC_LINES, Handler::Fn(nothing, 0)).is_err());
+}
@@ -0,0 +1,135 @@
+// Lookup of the interrupt controllers in the ACPI tables: the MADT ("APIC" table) lists the IO-APICs, and how the
+// ISA interrupt lines are wired to their inputs where it is not one to one (QEMU wires the PIT to input 2, e.g.).
+// The tables are found from the RSDP, which the BIOS places either in the first KiB of the EBDA or somewhere in
+// 0xe0000..0x100000, and are read through the physical memory mapping, once checked to be mapped.
+
+use x86_64::PhysAddr;
+
+use crate::memory::{self, walk, Translation};
+
+use super::irq::PIC_LINES;
+
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8] = b"APIC";
+const HEADER_SIZE: usize = 36; // Of every system description table
+const MADT_ENTRIES: usize = 44; // Offset of the first entry, after the local APIC address and flags
+
+// Kinds of MADT entries
+const IO_APIC: u8 = 1;
+const SOURCE_OVERRIDE: u8 = 2;
+
+/// How an ISA interrupt line is wired to the IO-APICs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub gsi: u32, // Global system interrupt, i.e. IO-APIC input numbered across all of them
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// What the MADT tells about the first IO-APIC
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub io_apic: PhysAddr,
+    pub gsi_base: u32, // Global system interrupt of its first input
+    pub isa_routes: [Route; PIC_LINES as usize],
+}
+
+/// Returns the `len` bytes of physical memory at `phys`, provided that they are mapped.
+fn physical(phys: u64, len: usize) -> Option<&'static [u8]> {
+    let start = memory::phys_to_virt(PhysAddr::try_new(phys).ok()?)?;
+    let mapped = |addr| matches!(walk::translate(addr), Some(Translation::Mapped { .. }));
+    if len == 0 || !mapped(start) || !mapped(start + (len as u64 - 1)) {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts(start.as_ptr(), len) })
+}
+
+/// Reads the `N` bytes at `offset`, if within `bytes`.
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    bytes.get(offset..offset + N)?.try_into().ok()
+}
+
+/// ACPI structures are valid if all their bytes sum up to 0.
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Returns the physical address of the RSDP.
+fn find_rsdp() -> Option<u64> {
+    let ebda = u64::from(u16::from_le_bytes(read(physical(0x40e, 2)?, 0)?)) << 4;
+    [(ebda, 1024), (0xe0000, 0x20000)].into_iter().filter(|&(start, _)| start != 0).find_map(|(start, len)| {
+        let area = physical(start, len)?;
+        let offset = (0..len - 20).step_by(16).find(|&offset| {
+            let rsdp = &area[offset..offset + 20];
+            rsdp.starts_with(RSDP_SIGNATURE) && checksum_ok(rsdp)
+        })?;
+        Some(start + offset as u64)
+    })
+}
+
+/// Returns the system description table at `phys`, provided that its checksum is right.
+fn table(phys: u64) -> Option<&'static [u8]> {
+    let length = u32::from_le_bytes(read(physical(phys, HEADER_SIZE)?, 4)?) as usize;
+    let table = physical(phys, length.max(HEADER_SIZE))?;
+    checksum_ok(table).then_some(table)
+}
+
+/// Returns the first system description table with the given signature, listed by the XSDT if the RSDP points
+/// to one (ACPI 2.0 on), or by the RSDT otherwise.
+fn find_table(signature: &[u8]) -> Option<&'static [u8]> {
+    let rsdp = find_rsdp()?;
+    let xsdt = match physical(rsdp, 20)?[15] {
+        0 | 1 => 0, // Revision
+        _ => u64::from_le_bytes(read(physical(rsdp, 36)?, 24)?),
+    };
+    let (root, entry_size) = match xsdt {
+        0 => (u64::from(u32::from_le_bytes(read(physical(rsdp, 20)?, 16)?)), 4),
+        xsdt => (xsdt, 8),
+    };
+    table(root)?[HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .filter_map(|entry| {
+            let mut addr = [0; 8];
+            addr[..entry_size].copy_from_slice(entry);
+            table(u64::from_le_bytes(addr))
+        })
+        .find(|table| table.starts_with(signature))
+}
+
+/// Reads the MADT, returning `None` if there is none or if it lists no IO-APIC.
+pub fn find() -> Option<Madt> {
+    let madt = find_table(MADT_SIGNATURE)?;
+    let mut io_apic = None;
+    let mut isa_routes = core::array::from_fn(|line| Route {
+        gsi: line as u32,
+        active_low: false,
+        level_triggered: false,
+    });
+
+    let mut offset = MADT_ENTRIES;
+    while let Some(&[kind, length]) = madt.get(offset..offset + 2) {
+        let entry = madt.get(offset..offset + usize::from(length))?;
+        match kind {
+            IO_APIC if io_apic.is_none() => {
+                io_apic = Some((u32::from_le_bytes(read(entry, 4)?), u32::from_le_bytes(read(entry, 8)?)));
+            }
+            SOURCE_OVERRIDE => {
+                let line = *entry.get(3)?;
+                let gsi = u32::from_le_bytes(read(entry, 4)?);
+                let flags = u16::from_le_bytes(read(entry, 8)?);
+                // Both fields are 0 when conforming to the bus, which means active high and edge triggered for ISA
+                if let Some(route) = isa_routes.get_mut(usize::from(line)) {
+                    *route = Route { gsi, active_low: flags & 0b11 == 0b11, level_triggered: flags & 0b1100 == 0b1100 };
+                }
+            }
+            _ => {}
+        }
+        if length == 0 {
+            break;
+        }
+        offset += usize::from(length);
+    }
+
+    let (io_apic, gsi_base) = io_apic?;
+    Some(Madt { io_apic: PhysAddr::new(u64::from(io_apic)), gsi_base, isa_routes })
+}
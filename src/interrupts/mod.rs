@@ -1,10 +1,16 @@
-use crate::hlt_loop;
-use crate::{gdt, print, println};
+use crate::memory::{self, VmmError, KERNEL_PAGING};
+use crate::{gdt, println};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
+
+pub mod apic;
+mod exceptions;
+pub mod irq;
+mod madt;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -29,15 +35,20 @@ impl InterruptIndex {
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        exceptions::install(&mut idt);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        irq::install(&mut idt);
+        idt[apic::SPURIOUS_VECTOR].set_handler_fn(apic::spurious_interrupt_handler);
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
         idt
     };
 }
@@ -50,53 +61,88 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Masks the PIC lines nothing handles yet, and registers the handler of the keyboard (the timer's being
+/// registered by `time::init`).
+///
+/// Must be called once the PICs are initialized, as doing so restores their previous masks.
+pub fn init_irqs() {
+    irq::mask_pic_lines();
+    irq::register(InterruptIndex::Keyboard.as_u8(), irq::Handler::Fn(keyboard_interrupt_handler, 0))
+        .expect("keyboard vector already taken");
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+/// Panics with a report if a fault at `addr` comes from a kernel stack hitting its guard page.
+///
+/// The paging structures are only tried for, as the fault may have happened while they were in use.
+fn check_stack_overflow(addr: Option<VirtAddr>, stack_frame: &InterruptStackFrame) {
+    let overflowed = addr.and_then(|addr| KERNEL_PAGING.try_lock()?.as_ref()?.vmm.overflowed_stack(addr));
+    if let Some(stack) = overflowed {
+        panic!(
+            "EXCEPTION: KERNEL STACK OVERFLOW\nStack: {:#x}..{:#x}\n{:#?}",
+            stack.start.as_u64(), stack.end().as_u64(), stack_frame
+        );
+    }
+}
+
 #[allow(dead_code)]
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // Overflowing a stack faults again when pushing the page fault's frame, which makes a double fault
+    check_stack_overflow(Cr2::read().ok(), &stack_frame);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+/// Maps the faulting page if it belongs to a lazy region (see `memory::vmm`), the access being retried
+/// once returning. Any other fault is a bug, and panics.
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
+    let addr = Cr2::read().ok();
+    let _nesting = gdt::PageFaultNesting::enter();
+    // Needs no lock, the heap being touched while the paging structures are in use
+    if addr.is_some_and(memory::demand_zero::map_on_fault) {
+        return;
+    }
+    check_stack_overflow(addr, &stack_frame);
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    //print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    // The paging structures are only tried for, as the fault may have happened while they were in use
+    let mut paging = KERNEL_PAGING.try_lock();
+    let (handled, region) = match (addr, paging.as_mut().and_then(|paging| paging.as_mut())) {
+        (Some(addr), Some(paging)) => (paging.handle_page_fault(addr, error_code), paging.vmm.find(addr)),
+        _ => (Err(VmmError::NotLazy), None),
+    };
+    let paging_locked = paging.is_none();
+    drop(paging);
+
+    if let Err(err) = handled {
+        panic!(
+            "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\nRegion: {:?}\n\
+            Translation: {:?}\nNot handled: {:?}{}\n{:#?}",
+            addr, error_code, region, addr.and_then(memory::walk::translate), err,
+            if paging_locked { " (paging structures in use)" } else { "" },
+            stack_frame
+        );
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    //print!("k");
-    //use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+fn keyboard_interrupt_handler(_context: usize) {
     use x86_64::instructions::port::Port;
 
-
     let mut port = Port::new(0x60);
 
     let scancode: u8 = unsafe { port.read() }; // Mandatory to unblock the char stream
-    crate::task::keyboard::add_scancode(scancode); 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    crate::task::keyboard::add_scancode(scancode);
 }
 
 #[test_case]
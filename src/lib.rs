@@ -8,6 +8,7 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 
 extern crate alloc;
 
@@ -18,6 +19,7 @@ use bootloader::{entry_point, BootInfo};
 entry_point!(test_kernel_main);
 
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 pub mod gdt;
 pub mod allocator;
@@ -26,12 +28,18 @@ pub mod memory;
 pub mod serial;
 pub mod vga;
 pub mod task;
+pub mod thread;
+pub mod time;
 
-/// Initializes GDT & interrupt environment (IDT, ...) + enables interrupts
+/// Initializes GDT & interrupt environment (IDT, ...) + starts the timer (see `time`) + enables interrupts
+///
+/// CPU memory protections are enabled by `memory::init` (see `memory::protect`)
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::init_irqs();
+    time::init(time::DEFAULT_FREQUENCY).expect("timer initialization failed");
     x86_64::instructions::interrupts::enable();
 }
 
@@ -48,8 +56,12 @@ pub enum QemuExitCode {
     // QEMU errors shall not be taken as kernel errors
     Success = 0x10,
     Failed = 0x11,
+    OutOfMemory = 0x12, // The heap could not serve an allocation (see `allocator`'s alloc error handler)
 }
 
+// Set by `test_runner`, so that fatal errors which do not panic still end the test run
+static TESTING: AtomicBool = AtomicBool::new(false);
+
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
@@ -73,6 +85,7 @@ impl<T: Fn()> Testable for T {
 
 // Runs 
 pub fn test_runner(tests: &[&dyn Testable]) {
+    TESTING.store(true, Ordering::SeqCst);
     serial_println!("Running {} tests", tests.len());
     for test in tests {
         test.run();
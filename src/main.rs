@@ -15,7 +15,7 @@ use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use burritos::allocator::linked_list::LinkedListAlloc;
 use bootloader::{entry_point, BootInfo};
 use burritos::{hlt_loop, memory};
-use burritos::memory::BootInfoFrameAllocator;
+use burritos::memory::{BuddyFrameAllocator, KERNEL_PAGING};
 use burritos::println;
 use burritos::task::{Task, task_executor::Executor, simple_executor::SimpleExecutor};
 use core::panic::PanicInfo;
@@ -41,7 +41,7 @@ fn panic(info: &PanicInfo) -> ! {
 // Main
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use burritos::allocator;
-    use x86_64::{structures::paging::Page, VirtAddr};
+    use x86_64::VirtAddr;
 
     // Init
     println!("Hello world!");
@@ -59,17 +59,23 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     // Mapper used to create new mappings (can induce the creation of new page table pages of level 4, 3 or 2)
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
 
-    let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
-    memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
+    // Alloc
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    burritos::gdt::init_ist_stacks().expect("interrupt stacks allocation failed");
+    match burritos::interrupts::apic::init() {
+        Ok(()) => burritos::time::use_apic_timer().expect("APIC timer calibration failed"),
+        Err(err) => println!("APIC unavailable ({:?}), keeping the 8259 PICs", err),
+    }
+    burritos::thread::init().expect("threads initialization failed");
 
+    // The kernel paging structures are now kept along with the heap
+    let page = memory::create_example_mapping(KERNEL_PAGING.lock().as_mut().unwrap()).expect("example mapping failed");
     let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
     unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
 
-    // Alloc
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
     let heap_val = Box::new(41);
     println!("heap_value at {:p}", heap_val);
 
@@ -0,0 +1,186 @@
+// Address spaces, each with a level 4 table of its own. The entries of the kernel (those set up by the bootloader,
+// and the windows of the kernel regions) are copied from the kernel's table, so that each address space shares
+// the lower level tables, and thus the kernel mappings, even those made afterwards.
+// Only the task window (see `RegionKind::Task`) is private to each address space, holding its user accessible regions.
+// Inactive address spaces are mapped through the physical memory mapping, as `KernelPaging::mapper` does for the
+// kernel's table.
+
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::{MapToError, UnmapError}, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTable, PageTableFlags, PageTableIndex, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{KernelPaging, KernelVmm, Region, RegionKind, VmmError};
+
+/// Kinds of the regions mapped in the kernel's table, and thus in every address space
+const KERNEL_KINDS: [RegionKind; 3] = [RegionKind::Heap, RegionKind::Stack, RegionKind::Mmio];
+const USER_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+fn window_index(kind: RegionKind) -> PageTableIndex {
+    Page::<Size4KiB>::containing_address(VirtAddr::new(kind.window_start())).p4_index()
+}
+
+/// An address space of its own, given back with `AddressSpace::free` (dropping it leaks it instead).
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    phys_offset: VirtAddr,
+    vmm: KernelVmm, // Regions of the task window only
+}
+
+impl AddressSpace {
+    /// Creates an address space with the kernel mappings, and no task region.
+    pub fn new(paging: &mut KernelPaging) -> Result<Self, VmmError> {
+        let phys_offset = paging.mapper.phys_offset();
+        // The windows of the kernel regions get their level 3 tables now, so that they are shared from then on
+        for kind in KERNEL_KINDS {
+            let entry = &mut paging.mapper.level_4_table_mut()[window_index(kind)];
+            if entry.is_unused() {
+                let frame = allocate_zeroed(&mut paging.frame_allocator, phys_offset)?;
+                entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            }
+        }
+
+        let level_4_frame = allocate_zeroed(&mut paging.frame_allocator, phys_offset)?;
+        let space = AddressSpace { level_4_frame, phys_offset, vmm: KernelVmm::new() };
+        let level_4_table = unsafe { space.level_4_table() };
+        for (index, entry) in paging.mapper.level_4_table().iter().enumerate() {
+            if PageTableIndex::new(index as u16) != window_index(RegionKind::Task) {
+                level_4_table[index] = entry.clone();
+            }
+        }
+        Ok(space)
+    }
+
+    /// Physical frame of the level 4 table, as loaded in CR3
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads the address space in CR3.
+    ///
+    /// Unsafe as anything the kernel still uses in the task window of the current address space gets unmapped.
+    pub unsafe fn switch(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    /// Reserves a region of `pages` pages in the task window, and maps all of them to fresh zeroed frames,
+    /// accessible from user mode.
+    pub fn allocate_region(&mut self, paging: &mut KernelPaging, pages: u64) -> Result<Region, VmmError> {
+        let region = self.vmm.reserve(pages, RegionKind::Task)?;
+        for index in 0..pages {
+            if let Err(err) = self.map_page(paging, region.page(index)) {
+                self.free_region(paging, region)?;
+                return Err(err);
+            }
+        }
+        Ok(region)
+    }
+
+    fn map_page(&mut self, paging: &mut KernelPaging, page: Page) -> Result<(), VmmError> {
+        let frame = allocate_zeroed(&mut paging.frame_allocator, self.phys_offset)?;
+        let active = self.is_active();
+        match unsafe { self.mapper().map_to(page, frame, USER_FLAGS, &mut paging.frame_allocator) } {
+            Ok(flush) if active => flush.flush(),
+            Ok(flush) => flush.ignore(), // Not in the TLB
+            Err(err) => {
+                unsafe { paging.frame_allocator.deallocate_frame(frame) };
+                return Err(VmmError::MapFailed(err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps a region, giving back its frames, and releases it.
+    pub fn free_region(&mut self, paging: &mut KernelPaging, region: Region) -> Result<(), VmmError> {
+        let active = self.is_active();
+        for index in 0..region.pages {
+            let (frame, flush) = match self.mapper().unmap(region.page(index)) {
+                Ok(unmapped) => unmapped,
+                Err(UnmapError::PageNotMapped) => continue,
+                Err(err) => return Err(VmmError::UnmapFailed(err)),
+            };
+            if active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+            unsafe { paging.frame_allocator.deallocate_frame(frame) };
+        }
+        self.vmm.release(region)
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = Region> + '_ {
+        self.vmm.regions()
+    }
+
+    /// Frees the pages of the task window and the tables holding them, then the level 4 table,
+    /// after switching back to the kernel's table if the address space was active.
+    pub fn free(self, paging: &mut KernelPaging) {
+        if self.is_active() {
+            let kernel_table = VirtAddr::from_ptr(paging.mapper.level_4_table() as *const PageTable);
+            let kernel_frame = PhysFrame::containing_address(PhysAddr::new(kernel_table - self.phys_offset));
+            unsafe { Cr3::write(kernel_frame, Cr3Flags::empty()) };
+        }
+        let entry = &unsafe { self.level_4_table() }[window_index(RegionKind::Task)];
+        if !entry.is_unused() {
+            unsafe { free_table(&mut paging.frame_allocator, self.phys_offset, entry.addr(), 3) };
+        }
+        unsafe { paging.frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+
+    /// Returns a mapper of the address space, active or not.
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(self.level_4_table(), self.phys_offset) }
+    }
+
+    /// Unsafe as the table must not be borrowed twice.
+    unsafe fn level_4_table(&self) -> &'static mut PageTable {
+        table(self.phys_offset, self.level_4_frame.start_address())
+    }
+}
+
+/// Unsafe as the table must not be borrowed twice.
+unsafe fn table(phys_offset: VirtAddr, phys: PhysAddr) -> &'static mut PageTable {
+    &mut *(phys_offset + phys.as_u64()).as_mut_ptr()
+}
+
+fn allocate_zeroed(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys_offset: VirtAddr,
+) -> Result<PhysFrame, VmmError> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(VmmError::MapFailed(MapToError::FrameAllocationFailed))?;
+    let bytes = (phys_offset + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+    unsafe { bytes.write_bytes(0, Size4KiB::SIZE as usize) };
+    Ok(frame)
+}
+
+/// Frees a table of the given level, every table below it, and the frames of the pages they map.
+///
+/// Unsafe as nothing must use these mappings anymore.
+unsafe fn free_table(
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    phys_offset: VirtAddr,
+    phys: PhysAddr,
+    level: usize,
+) {
+    for entry in table(phys_offset, phys).iter().filter(|entry| !entry.is_unused()) {
+        if level == 1 {
+            frame_allocator.deallocate_frame(PhysFrame::containing_address(entry.addr()));
+        } else {
+            free_table(frame_allocator, phys_offset, entry.addr(), level - 1);
+        }
+    }
+    frame_allocator.deallocate_frame(PhysFrame::containing_address(phys));
+}
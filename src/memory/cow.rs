@@ -0,0 +1,126 @@
+// Copy-on-write pages: a frame mapped read-only at several pages, possibly in different address spaces,
+// until one of them gets written to. The page fault handler then gives the writer a copy of its own
+// (see `KernelPaging::handle_page_fault`), or merely makes the page writable again if it was the last one sharing it.
+// Shared frames count their references in the frame allocator, so that they are only freed with their last mapping.
+// Write faults in kernel mode rely on CR0.WP, which the bootloader sets.
+
+use x86_64::structures::paging::{
+    mapper::{MappedFrame, MapToError, TranslateResult}, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable,
+    Page, PageSize, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+
+use super::{BuddyFrameAllocator, KernelPaging, Region, RegionKind, VmmError};
+
+/// Marks pages that were writable before being shared (one of the bits left to the OS)
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Returns the frame mapping `page` and the flags it is mapped with, if mapped by a 4 KiB page
+/// (huge pages are not shared).
+fn mapped(mapper: &OffsetPageTable, page: Page) -> Result<(PhysFrame, PageTableFlags), VmmError> {
+    match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => {
+            Ok((frame, flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY))
+        }
+        TranslateResult::Mapped { .. } => Err(VmmError::HugePage),
+        _ => Err(VmmError::NotMapped),
+    }
+}
+
+/// Write-protects `page` so that its frame can be shared: it becomes copy-on-write if it was writable.
+///
+/// Returns its frame and the flags to map it with elsewhere (see `map_shared`).
+pub fn protect(mapper: &mut OffsetPageTable, page: Page) -> Result<(PhysFrame, PageTableFlags), VmmError> {
+    let (frame, flags) = mapped(mapper, page)?;
+    if !flags.contains(PageTableFlags::WRITABLE) {
+        return Ok((frame, flags)); // Either read-only or copy-on-write already
+    }
+    let flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+    // Cannot fail, the page being mapped by a page table
+    unsafe { mapper.update_flags(page, flags).map_err(|_| VmmError::NotMapped)?.flush() };
+    Ok((frame, flags))
+}
+
+/// Maps at `page` a frame protected with `protect`, with the flags it returned, adding a reference to the frame.
+pub fn map_shared(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> Result<(), VmmError> {
+    frame_allocator.share(frame);
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { frame_allocator.deallocate_frame(frame) }; // Drops the reference again
+            Err(VmmError::MapFailed(err))
+        }
+    }
+}
+
+/// Makes a copy-on-write page writable, copying its frame first unless no other page shares it anymore.
+pub fn resolve_write(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> Result<(), VmmError> {
+    let (frame, flags) = mapped(mapper, page)?;
+    if !flags.contains(COPY_ON_WRITE) {
+        return Err(VmmError::NotCow);
+    }
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if frame_allocator.references(frame) == 1 {
+        unsafe { mapper.update_flags(page, flags).map_err(|_| VmmError::NotMapped)?.flush() };
+        return Ok(());
+    }
+
+    let copy: PhysFrame = frame_allocator
+        .allocate_frame()
+        .ok_or(VmmError::MapFailed(MapToError::FrameAllocationFailed))?;
+    let offset = mapper.phys_offset();
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            (offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+            (offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+            Size4KiB::SIZE as usize,
+        );
+        // The page table is kept, so remapping cannot fail
+        let (_, flush) = mapper.unmap(page).map_err(VmmError::UnmapFailed)?;
+        flush.ignore();
+        mapper.map_to(page, copy, flags, frame_allocator).map_err(VmmError::MapFailed)?.flush();
+        frame_allocator.deallocate_frame(frame); // Drops this page's reference
+    }
+    Ok(())
+}
+
+impl KernelPaging {
+    /// Reserves a region of the same kind and size as `region`, whose mapped pages share the frames of `region`
+    /// copy-on-write. Pages of lazy regions which are not mapped yet are later mapped separately in each region.
+    ///
+    /// Must not be used on the heap, which could not be written to while the paging structures are locked.
+    pub fn clone_region(&mut self, region: &Region) -> Result<Region, VmmError> {
+        if region.kind == RegionKind::Mmio {
+            return Err(VmmError::NotCow); // Device memory cannot be copied
+        }
+        let mut clone = self.vmm.reserve(region.pages, region.kind)?;
+        if region.lazy {
+            clone = self.vmm.make_lazy(clone)?;
+        }
+        for index in 0..region.pages {
+            let shared = protect(&mut self.mapper, region.page(index)).and_then(|(frame, flags)| {
+                map_shared(&mut self.mapper, clone.page(index), frame, flags, &mut self.frame_allocator)
+            });
+            match shared {
+                Ok(()) | Err(VmmError::NotMapped) => {} // Guard and lazy pages are left unmapped
+                Err(err) => {
+                    self.free_region(clone)?;
+                    return Err(err);
+                }
+            }
+        }
+        Ok(clone)
+    }
+}
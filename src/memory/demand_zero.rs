@@ -0,0 +1,100 @@
+// Demand-zero pages: pages whose frame is reserved when they are committed, but only zeroed and mapped on first
+// access. Their entry already holds the frame, without the present bit but marked with `DEMAND_ZERO`, so that the
+// page fault handler maps them by merely setting that bit (see `map_on_fault`). Unlike the pages of lazy regions,
+// this needs neither the frame allocator nor `KERNEL_PAGING`, and thus works while they are in use: the heap grows
+// this way, as it is touched with the paging structures locked, and grown with its allocator locked.
+// Committed pages are never given back, `KernelPaging::free_region` taking them as unmapped, so only the heap,
+// which is never freed, holds some.
+
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::huge::HUGE_PAGES;
+use super::{phys_to_virt, walk, BuddyFrameAllocator, KernelPaging, Region};
+
+/// Marks the entries of demand-zero pages not mapped yet (one of the bits left to the OS)
+pub const DEMAND_ZERO: PageTableFlags = PageTableFlags::BIT_10;
+
+impl KernelPaging {
+    /// Commits up to `count` pages of a region from its `first`th page on, each with a frame of its own,
+    /// with 2 MiB pages where alignment allows as `map_pages` does. Their content is zeroed on first access.
+    ///
+    /// Returns the number of pages committed, all of them coming before the first one which could not be
+    /// (e.g. once no frame is left).
+    pub fn commit_pages(&mut self, region: &Region, first: u64, count: u64) -> u64 {
+        let flags = (region.kind.flags() - PageTableFlags::PRESENT) | DEMAND_ZERO;
+        let mut index = first;
+        while index < first + count {
+            let addr = region.page(index).start_address();
+            if first + count - index >= HUGE_PAGES
+                && addr.is_aligned(Size2MiB::SIZE)
+                && self.commit_page::<Size2MiB>(addr, flags)
+            {
+                index += HUGE_PAGES;
+            } else if self.commit_page::<Size4KiB>(addr, flags) {
+                index += 1;
+            } else {
+                break;
+            }
+        }
+        index - first
+    }
+
+    /// Writes a demand-zero entry with a fresh frame for the page of size `S` at `addr`, creating the page tables
+    /// leading to it as needed.
+    ///
+    /// Returns whether it could, i.e. a frame was left and no page of another size is in the way.
+    fn commit_page<S: PageSize>(&mut self, addr: VirtAddr, flags: PageTableFlags) -> bool
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+        BuddyFrameAllocator: FrameAllocator<S> + FrameDeallocator<S>,
+    {
+        let Some(frame) = FrameAllocator::<S>::allocate_frame(&mut self.frame_allocator) else {
+            return false;
+        };
+        let page = Page::<S>::containing_address(addr);
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let map = unsafe {
+            self.mapper.map_to_with_table_flags(page, frame, flags, table_flags, &mut self.frame_allocator)
+        };
+        match map {
+            Ok(flush) => {
+                flush.ignore(); // Entries which are not present are never cached
+                true
+            }
+            Err(_) => {
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+                false
+            }
+        }
+    }
+}
+
+/// Zeroes the frame of the demand-zero page holding `addr` and maps it, if there is such a page.
+///
+/// Called by the page fault handler, before anything else as it takes no lock: the faulting access can then be
+/// retried. Returns whether the page was demand-zero.
+pub fn map_on_fault(addr: VirtAddr) -> bool {
+    let Some((entry, level)) = walk::last_entry(addr) else {
+        return false;
+    };
+    let flags = entry.flags();
+    if flags.contains(PageTableFlags::PRESENT) || !flags.contains(DEMAND_ZERO) {
+        return false;
+    }
+    let size = match level {
+        1 => Size4KiB::SIZE,
+        2 => Size2MiB::SIZE,
+        _ => return false,
+    };
+    let Some(frame) = phys_to_virt(entry.addr()) else {
+        return false;
+    };
+    unsafe { frame.as_mut_ptr::<u8>().write_bytes(0, size as usize) };
+    entry.set_flags((flags - DEMAND_ZERO) | PageTableFlags::PRESENT);
+    true
+}
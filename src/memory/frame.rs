@@ -0,0 +1,234 @@
+// Buddy allocator for physical frames. Free memory is kept as blocks of 2^order frames, from a single 4 KiB frame
+// (order 0) up to a 2 MiB frame (order MAX_ORDER), each order having its own free list.
+// Allocation pops a block from the smallest non-empty list of sufficient order, splitting it in halves (buddies)
+// down to the requested order. Deallocation merges a block with its buddy as long as the latter is free too.
+// Both are thus O(log n), with n the number of frames in a 2 MiB block.
+//
+// The free lists are doubly linked through the free frames themselves, accessed through the physical memory mapping
+// set up by the bootloader, and a bitmap per order tells whether a given block is free (i.e. part of the list).
+// Frames mapped at several pages (see `memory::cow`) also count their extra references, so that they only get freed
+// with their last mapping. These bitmaps and counts are the only metadata, and are stored at the start of a usable
+// region of physical memory.
+
+use core::fmt::Display;
+use core::slice;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB},
+};
+
+pub const MAX_ORDER: usize = 9; // 2^9 frames of 4 KiB = 2 MiB
+const ORDERS: usize = MAX_ORDER + 1;
+const FRAME_SIZE: u64 = 4096;
+const NONE: u64 = u64::MAX; // Null physical address in the free lists (frame 0 is never usable anyway)
+
+/// Written at the start of each free block
+struct FreeBlock {
+    prev: u64,
+    next: u64,
+}
+
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    free_lists: [u64; ORDERS], // Physical address of the first free block of each order
+    free_maps: [&'static mut [u64]; ORDERS], // One bit per block of each order, set if free
+    free_blocks: [usize; ORDERS],
+    share_counts: &'static mut [u16], // Extra references to each frame, 0 unless shared
+}
+
+impl BuddyFrameAllocator {
+    /// Create a frame allocator from the passed memory map, with every usable frame free
+    /// (except for those holding the allocator's bitmaps).
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. The main requirement is that all frames that are
+    /// marked as `USABLE` in it are really unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| r.range.start_addr()..r.range.end_addr())
+        };
+
+        // Bitmaps cover every frame up to the end of the last usable region
+        let frames = usable_regions().map(|r| r.end / FRAME_SIZE).max().unwrap_or(0);
+        let words = |order: usize| (frames >> order).div_ceil(64) as usize;
+        let bitmaps_size = ((0..ORDERS).map(words).sum::<usize>() * 8) as u64;
+        let metadata_size = (bitmaps_size + frames * 2).next_multiple_of(FRAME_SIZE);
+        let metadata_start = usable_regions()
+            .find(|r| r.end - r.start >= metadata_size)
+            .expect("no usable region can hold the frame allocator bitmaps")
+            .start;
+
+        let metadata = physical_memory_offset + metadata_start;
+        let mut remaining = slice::from_raw_parts_mut(metadata.as_mut_ptr::<u64>(), (bitmaps_size / 8) as usize);
+        remaining.fill(0);
+        let share_counts = slice::from_raw_parts_mut((metadata + bitmaps_size).as_mut_ptr::<u16>(), frames as usize);
+        share_counts.fill(0);
+        let free_maps = core::array::from_fn(|order| {
+            let (map, rest) = core::mem::take(&mut remaining).split_at_mut(words(order));
+            remaining = rest;
+            map
+        });
+
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            free_lists: [NONE; ORDERS],
+            free_maps,
+            free_blocks: [0; ORDERS],
+            share_counts,
+        };
+
+        for region in usable_regions() {
+            let start = if region.start == metadata_start { region.start + metadata_size } else { region.start };
+            for addr in (start..region.end).step_by(FRAME_SIZE as usize) {
+                allocator.deallocate_block(addr, 0); // Merged into bigger blocks along the way
+            }
+        }
+        allocator
+    }
+
+    /// Number of free 4 KiB frames
+    pub fn free_frames(&self) -> usize {
+        (0..ORDERS).map(|order| self.free_blocks[order] << order).sum()
+    }
+
+    /// Number of free blocks of 2^order frames
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_blocks[order]
+    }
+
+    /// Adds a reference to an allocated frame, which then takes one more deallocation to be freed.
+    pub fn share(&mut self, frame: PhysFrame<Size4KiB>) {
+        let count = &mut self.share_counts[(frame.start_address().as_u64() / FRAME_SIZE) as usize];
+        *count = count.checked_add(1).expect("frame shared too many times");
+    }
+
+    /// Number of references to an allocated frame: 1, plus one per `share`
+    pub fn references(&self, frame: PhysFrame<Size4KiB>) -> usize {
+        1 + self.share_counts[(frame.start_address().as_u64() / FRAME_SIZE) as usize] as usize
+    }
+
+    /// Allocates a block of 2^order frames, aligned on its size.
+    fn allocate_block(&mut self, order: usize) -> Option<u64> {
+        let available = (order..ORDERS).find(|&k| self.free_lists[k] != NONE)?;
+        let addr = self.free_lists[available];
+        self.remove(addr, available);
+
+        // Splits the block down to the requested order, giving back the upper halves
+        for k in (order..available).rev() {
+            self.push(addr + (FRAME_SIZE << k), k);
+        }
+        Some(addr)
+    }
+
+    /// Gives back a block of 2^order frames, merging it with its buddy as long as the latter is free.
+    fn deallocate_block(&mut self, mut addr: u64, mut order: usize) {
+        assert_eq!(addr % (FRAME_SIZE << order), 0, "misaligned block");
+        while order < MAX_ORDER {
+            let buddy = addr ^ (FRAME_SIZE << order);
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    /// Returns the bitmap word and bit of the block at `addr`, if it is covered by the bitmaps
+    fn bit(&self, addr: u64, order: usize) -> Option<(usize, u64)> {
+        let index = ((addr / FRAME_SIZE) >> order) as usize;
+        if index / 64 < self.free_maps[order].len() {
+            Some((index / 64, 1 << (index % 64)))
+        } else {
+            None
+        }
+    }
+
+    fn is_free(&self, addr: u64, order: usize) -> bool {
+        self.bit(addr, order)
+            .map_or(false, |(word, mask)| self.free_maps[order][word] & mask != 0)
+    }
+
+    fn block(&self, addr: u64) -> *mut FreeBlock {
+        (self.physical_memory_offset + addr).as_mut_ptr()
+    }
+
+    /// Pushes a block to the front of its free list.
+    fn push(&mut self, addr: u64, order: usize) {
+        let (word, mask) = self.bit(addr, order).expect("block out of the managed memory");
+        assert_eq!(self.free_maps[order][word] & mask, 0, "block freed twice");
+        self.free_maps[order][word] |= mask;
+
+        let head = self.free_lists[order];
+        unsafe {
+            self.block(addr).write(FreeBlock { prev: NONE, next: head });
+            if head != NONE {
+                (*self.block(head)).prev = addr;
+            }
+        }
+        self.free_lists[order] = addr;
+        self.free_blocks[order] += 1;
+    }
+
+    /// Unlinks a free block from its free list, wherever it is in it.
+    fn remove(&mut self, addr: u64, order: usize) {
+        let (word, mask) = self.bit(addr, order).expect("block out of the managed memory");
+        self.free_maps[order][word] &= !mask;
+
+        let FreeBlock { prev, next } = unsafe { self.block(addr).read() };
+        if prev == NONE {
+            self.free_lists[order] = next;
+        } else {
+            unsafe { (*self.block(prev)).next = next };
+        }
+        if next != NONE {
+            unsafe { (*self.block(next)).prev = prev };
+        }
+        self.free_blocks[order] -= 1;
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let addr = self.allocate_block(0)?;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    /// Drops a reference to the frame, freeing it if it was the last one.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let addr = frame.start_address().as_u64();
+        if let Some(count) = self.share_counts.get_mut((addr / FRAME_SIZE) as usize).filter(|count| **count > 0) {
+            *count -= 1;
+            return;
+        }
+        self.deallocate_block(addr, 0);
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let addr = self.allocate_block(MAX_ORDER)?;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate_block(frame.start_address().as_u64(), MAX_ORDER);
+    }
+}
+
+impl Display for BuddyFrameAllocator {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} free frames (blocks per order: {:?})", self.free_frames(), self.free_blocks)
+    }
+}
@@ -0,0 +1,122 @@
+// Huge pages: 2 MiB pages, mapped by a level 2 entry, and 1 GiB ones, mapped by a level 3 entry if the CPU supports it.
+// A single TLB entry then covers a whole range, and no page table is needed below, so big ranges are mapped with
+// huge pages whenever both their virtual and physical addresses are aligned, falling back to 4 KiB pages otherwise.
+// Fresh memory is at most a 2 MiB frame, the biggest block of the frame allocator, so 1 GiB pages are only used
+// for device memory. The heap also grows 2 MiB at a time once its end is aligned on 2 MiB (see `allocator`).
+
+use core::arch::x86_64::__cpuid;
+
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError}, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{KernelPaging, Region, RegionKind, VmmError};
+
+/// Number of 4 KiB pages in a 2 MiB one
+pub const HUGE_PAGES: u64 = Size2MiB::SIZE / Size4KiB::SIZE;
+
+/// Whether the CPU supports 1 GiB pages, as reported by CPUID leaf 0x8000_0001
+pub fn gigabyte_pages_supported() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+}
+
+/// Converts the error of mapping a page of any size.
+fn map_error<S: PageSize>(err: MapToError<S>) -> VmmError {
+    VmmError::MapFailed(match err {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    })
+}
+
+impl KernelPaging {
+    /// Maps the 2 MiB of a region from its `index`th page on to a fresh zeroed 2 MiB frame,
+    /// provided that the page is 2 MiB aligned, and that none of these 2 MiB is mapped yet.
+    ///
+    /// Returns whether it could, 4 KiB pages being mapped otherwise.
+    pub(super) fn try_map_huge(&mut self, region: &Region, index: u64) -> bool {
+        let start = region.page(index).start_address();
+        if !start.is_aligned(Size2MiB::SIZE) || index + HUGE_PAGES > region.pages {
+            return false;
+        }
+        let Some(frame) = FrameAllocator::<Size2MiB>::allocate_frame(&mut self.frame_allocator) else {
+            return false;
+        };
+        let page = Page::<Size2MiB>::containing_address(start);
+        match unsafe { self.mapper.map_to(page, frame, region.kind.flags(), &mut self.frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+                return false;
+            }
+        }
+        unsafe { start.as_mut_ptr::<u8>().write_bytes(0, Size2MiB::SIZE as usize) };
+        true
+    }
+
+    /// Maps the page of size `S` at `addr` to the frame at `phys`.
+    pub(super) fn map_frame<S: PageSize>(
+        &mut self,
+        addr: VirtAddr,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), VmmError>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let page = Page::<S>::containing_address(addr);
+        let frame = PhysFrame::<S>::containing_address(phys);
+        match unsafe { self.mapper.map_to(page, frame, flags, &mut self.frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(err) => Err(map_error(err)),
+        }
+    }
+
+    /// Unmaps the page of any size mapped at `addr`, which is part of `region`, giving back its frame
+    /// unless it is device memory.
+    ///
+    /// Returns the number of 4 KiB pages it spanned, 1 if `addr` was not mapped.
+    pub(super) fn unmap_any(&mut self, region: &Region, addr: VirtAddr) -> Result<u64, VmmError> {
+        let device = region.kind == RegionKind::Mmio;
+        let size = match self.mapper.translate(addr) {
+            TranslateResult::NotMapped => return Ok(1), // Regions may be partly mapped, such as the heap
+            TranslateResult::InvalidFrameAddress(phys) => {
+                return Err(VmmError::UnmapFailed(UnmapError::InvalidFrameAddress(phys)))
+            }
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), .. } => {
+                let (frame, flush) = self.mapper.unmap(Page::<Size4KiB>::containing_address(addr))
+                    .map_err(VmmError::UnmapFailed)?;
+                flush.flush();
+                if !device {
+                    unsafe { self.frame_allocator.deallocate_frame(frame) };
+                }
+                Size4KiB::SIZE
+            }
+            TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), .. } => {
+                let (frame, flush) = self.mapper.unmap(Page::<Size2MiB>::containing_address(addr))
+                    .map_err(VmmError::UnmapFailed)?;
+                flush.flush();
+                if !device {
+                    unsafe { self.frame_allocator.deallocate_frame(frame) };
+                }
+                Size2MiB::SIZE
+            }
+            TranslateResult::Mapped { frame: MappedFrame::Size1GiB(_), .. } => {
+                let (_, flush) = self.mapper.unmap(Page::<Size1GiB>::containing_address(addr))
+                    .map_err(VmmError::UnmapFailed)?;
+                flush.flush(); // Only device memory is mapped with such pages
+                Size1GiB::SIZE
+            }
+        };
+        Ok(size / Size4KiB::SIZE)
+    }
+}
@@ -1,17 +1,63 @@
-use core::fmt::Display;
-
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
 use x86_64::{structures::paging::PageTable, VirtAddr};
 use x86_64::{
     PhysAddr,
-    structures::paging::{Page, PhysFrame, Mapper, Size4KiB, FrameAllocator, mapper::OffsetPageTable}
+    structures::paging::{Page, mapper::OffsetPageTable}
 };
 
-/// Creates and initializes an OffsetPageTable
+pub use address_space::AddressSpace;
+pub use frame::BuddyFrameAllocator;
+pub use stack::KernelStack;
+pub use vmm::{KernelVmm, Region, RegionKind, VmmError};
+pub use walk::{PageTableDump, Translation};
+pub mod address_space;
+pub mod cow;
+pub mod demand_zero;
+pub mod frame;
+pub mod huge;
+pub mod protect;
+pub mod stack;
+pub mod vmm;
+pub mod walk;
+
+/// The page mapper, frame allocator and virtual memory manager the kernel keeps once booted,
+/// so that memory can still be mapped on demand (e.g. to grow the heap).
+pub struct KernelPaging {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BuddyFrameAllocator,
+    pub vmm: KernelVmm,
+}
+
+impl KernelPaging {
+    /// No kernel region is reserved at first, not even the heap's
+    pub fn new(mapper: OffsetPageTable<'static>, frame_allocator: BuddyFrameAllocator) -> Self {
+        KernelPaging {
+            mapper,
+            frame_allocator,
+            vmm: KernelVmm::new(),
+        }
+    }
+}
+
+/// Handed over by `allocator::init_heap`, and `None` until then
+pub static KERNEL_PAGING: Mutex<Option<KernelPaging>> = Mutex::new(None);
+
+/// Creates and initializes an OffsetPageTable, after enabling the CPU memory protections and remapping
+/// the kernel image with the rights of its segments (see `protect`)
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    walk::PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), core::sync::atomic::Ordering::SeqCst);
     // access to physical address of the lvl 4 page table
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    let mut mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
+    protect::enable_cpu_protections();
+    protect::protect_kernel_image(&mut mapper);
+    mapper
+}
+
+/// Returns the address at which the physical memory mapping maps `phys`, or `None` before `init`.
+pub fn phys_to_virt(phys: PhysAddr) -> Option<VirtAddr> {
+    let offset = walk::PHYSICAL_MEMORY_OFFSET.load(core::sync::atomic::Ordering::SeqCst);
+    (offset != 0).then(|| VirtAddr::new(offset + phys.as_u64()))
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -35,63 +81,10 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     &mut *(virt.as_mut_ptr() as *mut PageTable) // unsafe
 }
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-
-    /// Creates an iterator over the usable frames specified in the memory map
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
-
-impl Display for BootInfoFrameAllocator {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:?}", self.memory_map)
-    }
+/// Creates an example mapping of the VGA text buffer (frame `0xb8000`) in a new MMIO region.
+///
+/// Returns the page it is mapped at.
+pub fn create_example_mapping(paging: &mut KernelPaging) -> Result<Page, VmmError> {
+    let region = paging.map_mmio(PhysAddr::new(0xb8000), 4096)?;
+    Ok(region.page(0))
 }
-
-/// Creates an example mapping for the given page to frame `0xb8000`.
-pub fn create_example_mapping(
-    page: Page,
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    use x86_64::structures::paging::PageTableFlags as Flags;
-
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000)); // Find the physical frame containing addr 0xb8000
-    let flags = Flags::PRESENT | Flags::WRITABLE;
-
-    let mappping = unsafe {
-        // FIXME: this is not safe, we do it only for testing
-        // Indeed, the mapping may be already present, leading to UB
-        mapper.map_to(page, frame, flags, frame_allocator)
-    };
-    mappping.expect("map_to failed").flush();
-}
\ No newline at end of file
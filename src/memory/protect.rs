@@ -0,0 +1,111 @@
+// Hardening of the kernel mappings, so that code cannot be written to and data cannot be executed.
+// Both sides are set up by `memory::init`. The CPU one first (see `enable_cpu_protections`): no-execute pages,
+// write protection applying to the kernel too, and if the CPU model supports them SMEP and SMAP, which forbid the
+// kernel to execute or access user pages. The pages of the kernel image are then remapped with the rights of their
+// ELF segments, read from the program headers which the bootloader maps along with the first segment.
+// The regions of the kernel VMM are already mapped no-execute (see `RegionKind::flags`).
+
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult}, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+extern "C" {
+    // Defined by the linker at the ELF header
+    static __ehdr_start: u8;
+}
+
+/// ELF64 program header
+#[repr(C)]
+#[allow(dead_code)] // Laid out as in the file
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+/// Protections the CPU model supports, as reported by CPUID leaf 7
+fn supported_protections() -> Cr4Flags {
+    let mut flags = Cr4Flags::empty();
+    if __cpuid(0).eax >= 7 {
+        let features = __cpuid_count(7, 0).ebx;
+        flags.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, features & (1 << 7) != 0);
+        flags.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, features & (1 << 20) != 0);
+    }
+    flags
+}
+
+/// Enables no-execute pages, write protection of read-only pages in kernel mode, and SMEP and SMAP if supported.
+///
+/// Called by `memory::init`, as no-execute pages must be enabled before mapping any, the bit being reserved
+/// otherwise.
+pub fn enable_cpu_protections() {
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+        Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+        Cr4::update(|flags| *flags |= supported_protections());
+    }
+}
+
+/// Runs `f` with SMAP lifted, so that it can access user pages (e.g. to copy to or from them).
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+    if smap {
+        unsafe { asm!("stac", options(nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+    result
+}
+
+/// Returns the program headers of the kernel image.
+fn program_headers() -> &'static [ProgramHeader] {
+    unsafe {
+        let header = &__ehdr_start as *const u8;
+        assert_eq!(core::slice::from_raw_parts(header, 4), b"\x7fELF", "ELF header not mapped");
+        let offset = header.add(0x20).cast::<u64>().read_unaligned(); // e_phoff
+        let count = header.add(0x38).cast::<u16>().read_unaligned(); // e_phnum
+        core::slice::from_raw_parts(header.add(offset as usize).cast(), count as usize)
+    }
+}
+
+/// Remaps each page of the kernel image with the rights of its segment: code read-only and executable,
+/// read-only data read-only and no-execute, and writable data no-execute.
+pub fn protect_kernel_image(mapper: &mut OffsetPageTable) {
+    for segment in program_headers().iter().filter(|segment| segment.kind == PT_LOAD && segment.memory_size > 0) {
+        let mut rights = PageTableFlags::empty();
+        rights.set(PageTableFlags::WRITABLE, segment.flags & PF_W != 0);
+        rights.set(PageTableFlags::NO_EXECUTE, segment.flags & PF_X == 0);
+
+        let start = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.vaddr));
+        let end = Page::containing_address(VirtAddr::new(segment.vaddr + segment.memory_size - 1));
+        for page in Page::range_inclusive(start, end) {
+            let flags = match mapper.translate(page.start_address()) {
+                TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), flags, .. } => flags,
+                _ => continue, // The bootloader maps the kernel with 4 KiB pages
+            };
+            let flags = (flags - PageTableFlags::WRITABLE - PageTableFlags::NO_EXECUTE) | rights;
+            unsafe { mapper.update_flags(page, flags).expect("kernel page not mapped").flush() };
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Kernel stacks allocated at runtime, each in its own `Stack` region whose lowest page is left unmapped.
+// A stack overflowing thus hits that guard page and faults, instead of silently overwriting whatever lies below,
+// and the fault handlers tell such overflows apart through `KernelVmm::overflowed_stack`.
+// Stacks may also be lazily committed, their pages being mapped by the page fault handler as the stack grows,
+// which it can as it runs on an interrupt stack of its own.
+
+use x86_64::{
+    structures::paging::{PageSize, Size4KiB},
+    VirtAddr,
+};
+
+use super::{KernelPaging, KernelVmm, Region, RegionKind, VmmError};
+
+const GUARD_PAGES: u64 = 1;
+
+/// A kernel stack, given back with `KernelPaging::free_stack` (dropping it leaks it instead).
+#[derive(Debug)]
+pub struct KernelStack {
+    region: Region, // Guard page included
+}
+
+impl KernelStack {
+    /// Initial stack pointer, the stack growing downwards
+    pub fn top(&self) -> VirtAddr {
+        self.region.end()
+    }
+
+    /// Lowest usable address
+    pub fn bottom(&self) -> VirtAddr {
+        self.region.start + GUARD_PAGES * Size4KiB::SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.top() - self.bottom()
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.bottom() <= addr && addr < self.top()
+    }
+}
+
+impl KernelPaging {
+    /// Allocates a stack of `pages` mapped pages, below which a guard page is kept unmapped.
+    pub fn allocate_stack(&mut self, pages: u64) -> Result<KernelStack, VmmError> {
+        let region = self.vmm.reserve(pages + GUARD_PAGES, RegionKind::Stack)?;
+        if let Err(err) = self.map_pages(&region, GUARD_PAGES, pages) {
+            self.free_region(region)?;
+            return Err(err);
+        }
+        Ok(KernelStack { region })
+    }
+
+    /// Like `allocate_stack`, except that the pages are only mapped when first used (see `KernelVmm::make_lazy`).
+    ///
+    /// Must not be used for interrupt stacks, as the page fault handler could not run on them.
+    pub fn allocate_lazy_stack(&mut self, pages: u64) -> Result<KernelStack, VmmError> {
+        let region = self.vmm.reserve(pages + GUARD_PAGES, RegionKind::Stack)?;
+        let region = self.vmm.make_lazy(region)?;
+        Ok(KernelStack { region })
+    }
+
+    /// Unmaps a stack, which must not be in use anymore.
+    pub fn free_stack(&mut self, stack: KernelStack) -> Result<(), VmmError> {
+        self.free_region(stack.region)
+    }
+}
+
+impl KernelVmm {
+    /// Returns the stack region whose guard page holds `addr`, i.e. the stack which overflowed if `addr` faulted.
+    pub fn overflowed_stack(&self, addr: VirtAddr) -> Option<Region> {
+        self.find(addr).filter(|region| {
+            region.kind == RegionKind::Stack && addr < region.start + GUARD_PAGES * Size4KiB::SIZE
+        })
+    }
+}
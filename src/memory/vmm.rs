@@ -0,0 +1,334 @@
+// Kernel virtual memory manager: keeps track of the ranges of virtual addresses in use by the kernel (regions),
+// so that nothing gets mapped twice, and maps or unmaps them with the page flags fitting their use.
+// Each kind of region gets its own window of the address space (one level 4 table entry, i.e. 512 GiB), in which
+// new regions are placed first-fit. Regions may also be reserved at a fixed address, such as the heap.
+// The table of regions is a fixed-size array, as the heap itself is one of the regions.
+// Lazy regions are only mapped on demand: the page fault handler maps a zeroed frame at the faulting page
+// (see `KernelPaging::handle_page_fault`), so that memory is only committed once used. The heap is committed
+// ahead instead, with demand-zero pages (see `memory::demand_zero`).
+
+use core::fmt;
+
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MapToError, UnmapError}, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize,
+            PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::huge::{self, HUGE_PAGES};
+use super::{cow, KernelPaging};
+
+const MAX_REGIONS: usize = 64;
+const WINDOW_SIZE: u64 = 512 * 1024 * 1024 * 1024; // Covered by a single level 4 entry
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Heap,
+    Stack,
+    Mmio, // Device memory, mapped to given frames rather than fresh ones
+    Task, // Memory owned by a single task
+}
+
+impl RegionKind {
+    /// First address of the window where regions of this kind are placed
+    pub(super) fn window_start(self) -> u64 {
+        match self {
+            RegionKind::Heap => 0x_4400_0000_0000, // Holds `allocator::HEAP_START`
+            RegionKind::Stack => 0x_5500_0000_0000,
+            RegionKind::Mmio => 0x_6600_0000_0000,
+            RegionKind::Task => 0x_7700_0000_0000,
+        }
+    }
+
+    /// Flags of the pages mapped in regions of this kind, which all hold data
+    pub fn flags(self) -> PageTableFlags {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        match self {
+            RegionKind::Mmio => flags | PageTableFlags::NO_CACHE,
+            _ => flags,
+        }
+    }
+}
+
+/// A range of kernel virtual memory reserved for a given use, whose pages may or may not be mapped yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub pages: u64,
+    pub kind: RegionKind,
+    pub lazy: bool, // Pages are mapped on first access
+}
+
+impl Region {
+    /// Exclusive end address
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.pages * PAGE_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.pages * PAGE_SIZE
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Returns the `index`th page of the region.
+    pub fn page(&self, index: u64) -> Page {
+        Page::containing_address(self.start + index * PAGE_SIZE)
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start < end && start < self.end()
+    }
+}
+
+#[derive(Debug)]
+pub enum VmmError {
+    Overlap(Region), // With the given region
+    Misaligned,
+    OutOfVirtualSpace,
+    TooManyRegions,
+    NotReserved,
+    NotLazy, // A page fault happened outside of any lazy region
+    NotMapped, // Not mapped by a 4 KiB page
+    NotCow, // A write fault happened on a page which is not copy-on-write
+    HugePage, // Mapped by a huge page where a 4 KiB one is needed
+    MapFailed(MapToError<Size4KiB>),
+    UnmapFailed(UnmapError),
+}
+
+/// Table of the kernel regions
+pub struct KernelVmm {
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+impl KernelVmm {
+    pub const fn new() -> Self {
+        KernelVmm {
+            regions: [None; MAX_REGIONS],
+        }
+    }
+
+    /// Reserves `pages` pages at `start`, provided they do not overlap any other region.
+    pub fn reserve_at(&mut self, start: VirtAddr, pages: u64, kind: RegionKind) -> Result<Region, VmmError> {
+        if !start.is_aligned(PAGE_SIZE) {
+            return Err(VmmError::Misaligned);
+        }
+        // The end must still be a valid address
+        pages
+            .checked_mul(PAGE_SIZE)
+            .and_then(|size| start.as_u64().checked_add(size))
+            .and_then(|end| VirtAddr::try_new(end).ok())
+            .ok_or(VmmError::OutOfVirtualSpace)?;
+        let region = Region { start, pages, kind, lazy: false };
+        if let Some(other) = self.overlapping(region.start, region.end()) {
+            return Err(VmmError::Overlap(other));
+        }
+        let slot = self.regions.iter_mut().find(|slot| slot.is_none()).ok_or(VmmError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(region)
+    }
+
+    /// Reserves `pages` pages anywhere in the window of `kind`.
+    pub fn reserve(&mut self, pages: u64, kind: RegionKind) -> Result<Region, VmmError> {
+        self.reserve_aligned(pages, kind, PAGE_SIZE, 0)
+    }
+
+    /// Reserves `pages` pages anywhere in the window of `kind`, starting at `phase` bytes past a multiple of `align`
+    /// (so that huge pages can map physical memory at that same offset within them).
+    pub fn reserve_aligned(
+        &mut self,
+        pages: u64,
+        kind: RegionKind,
+        align: u64,
+        phase: u64,
+    ) -> Result<Region, VmmError> {
+        if pages > WINDOW_SIZE / PAGE_SIZE {
+            return Err(VmmError::OutOfVirtualSpace);
+        }
+        let size = pages * PAGE_SIZE;
+        let window_end = kind.window_start() + WINDOW_SIZE;
+        let aligned = |addr: VirtAddr| VirtAddr::new((addr.as_u64() - phase).next_multiple_of(align) + phase);
+        let mut start = aligned(VirtAddr::new(kind.window_start()));
+        // Skips past every region in the way, which ends within MAX_REGIONS steps
+        loop {
+            if start.as_u64() + size > window_end {
+                return Err(VmmError::OutOfVirtualSpace);
+            }
+            match self.overlapping(start, start + size) {
+                Some(other) => start = aligned(other.end()),
+                None => break,
+            }
+        }
+        self.reserve_at(start, pages, kind)
+    }
+
+    /// Makes a region lazy, so that its pages get mapped on first access.
+    ///
+    /// Returns the updated region.
+    pub fn make_lazy(&mut self, region: Region) -> Result<Region, VmmError> {
+        let slot = self
+            .regions
+            .iter_mut()
+            .flatten()
+            .find(|slot| **slot == region)
+            .ok_or(VmmError::NotReserved)?;
+        slot.lazy = true;
+        Ok(*slot)
+    }
+
+    /// Forgets a region, which must have been unmapped first.
+    pub fn release(&mut self, region: Region) -> Result<(), VmmError> {
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|slot| **slot == Some(region))
+            .ok_or(VmmError::NotReserved)?;
+        *slot = None;
+        Ok(())
+    }
+
+    /// Returns the region holding `addr`, if any.
+    pub fn find(&self, addr: VirtAddr) -> Option<Region> {
+        self.regions().find(|region| region.contains(addr))
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = Region> + '_ {
+        self.regions.iter().flatten().copied()
+    }
+
+    fn overlapping(&self, start: VirtAddr, end: VirtAddr) -> Option<Region> {
+        self.regions().find(|region| region.overlaps(start, end))
+    }
+}
+
+impl fmt::Display for KernelVmm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for region in self.regions() {
+            write!(f, "{:?}: {:#x}..{:#x}", region.kind, region.start.as_u64(), region.end().as_u64())?;
+            writeln!(f, "{}", if region.lazy { " (lazy)" } else { "" })?;
+        }
+        Ok(())
+    }
+}
+
+impl KernelPaging {
+    /// Reserves a region of `pages` pages of the given kind, and maps all of them.
+    pub fn allocate_region(&mut self, pages: u64, kind: RegionKind) -> Result<Region, VmmError> {
+        let region = self.vmm.reserve(pages, kind)?;
+        if let Err(err) = self.map_pages(&region, 0, pages) {
+            self.free_region(region)?;
+            return Err(err);
+        }
+        Ok(region)
+    }
+
+    /// Maps the page holding `addr` to a fresh zeroed frame, if it is part of a lazy region and not mapped yet,
+    /// or gives it a frame of its own if it is copy-on-write (see `memory::cow`) and was written to.
+    ///
+    /// Called by the page fault handler: the faulting access can then be retried.
+    pub fn handle_page_fault(&mut self, addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), VmmError> {
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            // The page is mapped already, but not with the rights needed
+            if !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                return Err(VmmError::NotCow);
+            }
+            return cow::resolve_write(&mut self.mapper, Page::containing_address(addr), &mut self.frame_allocator);
+        }
+        let region = self.vmm.find(addr).filter(|region| region.lazy).ok_or(VmmError::NotLazy)?;
+        if self.vmm.overflowed_stack(addr).is_some() {
+            return Err(VmmError::NotLazy); // Guard pages are never mapped
+        }
+        let index = (addr - region.start) / PAGE_SIZE;
+        self.map_pages(&region, index, 1)
+    }
+
+    /// Maps `count` pages of a region from its `first`th page on, each to a fresh zeroed frame,
+    /// with 2 MiB pages where alignment allows (see `memory::huge`).
+    ///
+    /// The heap relies on that zeroing (see `BumpAlloc::init`).
+    pub fn map_pages(&mut self, region: &Region, first: u64, count: u64) -> Result<(), VmmError> {
+        assert!(first + count <= region.pages, "pages out of the region");
+        let mut index = first;
+        while index < first + count {
+            if first + count - index >= HUGE_PAGES && self.try_map_huge(region, index) {
+                index += HUGE_PAGES;
+                continue;
+            }
+            let frame = self
+                .frame_allocator
+                .allocate_frame()
+                .ok_or(VmmError::MapFailed(MapToError::FrameAllocationFailed))?;
+            let page = region.page(index);
+            unsafe {
+                match self.mapper.map_to(page, frame, region.kind.flags(), &mut self.frame_allocator) {
+                    Ok(flush) => flush.flush(),
+                    Err(err) => {
+                        self.frame_allocator.deallocate_frame(frame);
+                        return Err(VmmError::MapFailed(err));
+                    }
+                }
+                page.start_address().as_mut_ptr::<u8>().write_bytes(0, PAGE_SIZE as usize);
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Reserves an MMIO region covering the `size` bytes of device memory at `phys`, and maps it.
+    ///
+    /// Returns the region, whose start maps the frame containing `phys`. Big ranges are mapped with huge pages,
+    /// the region being placed at the same offset within them as `phys`.
+    pub fn map_mmio(&mut self, phys: PhysAddr, size: u64) -> Result<Region, VmmError> {
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+        let offset = phys - first_frame.start_address();
+        let pages = (offset + size).div_ceil(PAGE_SIZE);
+        let huge_size = if pages * PAGE_SIZE >= Size1GiB::SIZE && huge::gigabyte_pages_supported() {
+            Size1GiB::SIZE
+        } else if pages * PAGE_SIZE >= Size2MiB::SIZE {
+            Size2MiB::SIZE
+        } else {
+            PAGE_SIZE
+        };
+        let phase = first_frame.start_address().as_u64() % huge_size;
+        let region = self.vmm.reserve_aligned(pages, RegionKind::Mmio, huge_size, phase)?;
+
+        let mut index = 0;
+        while index < pages {
+            let addr = region.page(index).start_address();
+            let frame = first_frame.start_address() + index * PAGE_SIZE;
+            let fits = |size: u64| addr.is_aligned(size) && (pages - index) * PAGE_SIZE >= size;
+            let flags = region.kind.flags();
+            let (map, size) = if huge_size == Size1GiB::SIZE && fits(Size1GiB::SIZE) {
+                (self.map_frame::<Size1GiB>(addr, frame, flags), Size1GiB::SIZE)
+            } else if fits(Size2MiB::SIZE) {
+                (self.map_frame::<Size2MiB>(addr, frame, flags), Size2MiB::SIZE)
+            } else {
+                (self.map_frame::<Size4KiB>(addr, frame, flags), PAGE_SIZE)
+            };
+            if let Err(err) = map {
+                self.free_region(region)?;
+                return Err(err);
+            }
+            index += size / PAGE_SIZE;
+        }
+        Ok(region)
+    }
+
+    /// Unmaps every mapped page of a region, huge ones included, giving back their frames unless they are
+    /// device memory, and releases the region.
+    pub fn free_region(&mut self, region: Region) -> Result<(), VmmError> {
+        let mut index = 0;
+        while index < region.pages {
+            index += self.unmap_any(&region, region.page(index).start_address())?;
+        }
+        self.vmm.release(region)
+    }
+}
@@ -0,0 +1,204 @@
+// Page table walker, to inspect the mappings of the active address space while debugging,
+// or to find the entry of a faulting page.
+// The tables are read from the physical memory mapping, starting from CR3, without going through
+// `KERNEL_PAGING`: the walk thus needs no lock, and can be done from fault handlers and the panic path.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{page_table::PageTableEntry, PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+// Set by `memory::init`, 0 until then
+pub(super) static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Flags summed up over all the levels of a walk, as the CPU applies them
+const SHOWN_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE)
+    .union(PageTableFlags::HUGE_PAGE);
+
+/// Size of the memory mapped by an entry of a table of the given level (1 for page tables, 4 for the level 4 one)
+fn entry_size(level: usize) -> u64 {
+    1 << (12 + 9 * (level - 1))
+}
+
+/// A range of virtual memory mapped to contiguous physical memory, with the same flags throughout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: u64,
+    pub flags: PageTableFlags, // Effective ones among `SHOWN_FLAGS`
+}
+
+impl Mapping {
+    fn extends(&self, next: &Mapping) -> bool {
+        self.start + self.size == next.start && self.phys + self.size == next.phys && self.flags == next.flags
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |flag, name| if self.flags.contains(flag) { name } else { "-" };
+        write!(
+            f,
+            "{:#014x}..{:#014x} -> {:#012x} {} {} {} {} {}",
+            self.start.as_u64(),
+            (self.start + self.size).as_u64(),
+            self.phys.as_u64(),
+            flag(PageTableFlags::PRESENT, "P"),
+            flag(PageTableFlags::WRITABLE, "W"),
+            flag(PageTableFlags::USER_ACCESSIBLE, "U"),
+            flag(PageTableFlags::NO_EXECUTE, "NX"),
+            flag(PageTableFlags::HUGE_PAGE, "HUGE"),
+        )
+    }
+}
+
+/// Outcome of `translate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Translation {
+    /// Mapped by an entry of a table of the given level: 1 for a 4 KiB page, 2 for a 2 MiB one, 3 for a 1 GiB one
+    Mapped { phys: PhysAddr, level: usize, flags: PageTableFlags },
+    /// The entry of the table of the given level is not present
+    NotMapped { level: usize },
+}
+
+/// Returns the table at the given physical address, through the physical memory mapping.
+fn table(offset: u64, phys: PhysAddr) -> &'static PageTable {
+    unsafe { &*VirtAddr::new(offset + phys.as_u64()).as_ptr() }
+}
+
+/// Returns the active level 4 table, or `None` before `memory::init`.
+fn level_4_table() -> Option<(u64, &'static PageTable)> {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst);
+    if offset == 0 {
+        return None;
+    }
+    let (frame, _) = Cr3::read();
+    Some((offset, table(offset, frame.start_address())))
+}
+
+/// Sums up the flags of one more level: a page is only writable or user accessible if every level allows it,
+/// and not executable if any level forbids it.
+fn combine(flags: PageTableFlags, entry: PageTableFlags, level: usize) -> PageTableFlags {
+    let mut combined = flags & (entry | PageTableFlags::NO_EXECUTE | PageTableFlags::HUGE_PAGE);
+    combined |= entry & PageTableFlags::NO_EXECUTE;
+    // The same bit is the PAT one in page tables
+    combined.set(PageTableFlags::HUGE_PAGE, level > 1 && entry.contains(PageTableFlags::HUGE_PAGE));
+    combined
+}
+
+/// Walks the active page tables down to the entry mapping `addr`.
+///
+/// Returns `None` if the page tables cannot be read yet.
+pub fn translate(addr: VirtAddr) -> Option<Translation> {
+    let (offset, mut table_ref) = level_4_table()?;
+    let mut flags = SHOWN_FLAGS - PageTableFlags::NO_EXECUTE;
+    for level in (1..=4).rev() {
+        let index = (addr.as_u64() >> (12 + 9 * (level - 1))) as usize & 0x1ff;
+        let entry = &table_ref[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Some(Translation::NotMapped { level });
+        }
+        flags = combine(flags, entry.flags(), level);
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let phys = entry.addr() + (addr.as_u64() & (entry_size(level) - 1));
+            return Some(Translation::Mapped { phys, level, flags });
+        }
+        table_ref = table(offset, entry.addr());
+    }
+    unreachable!("page tables have 4 levels");
+}
+
+/// Walks the active page tables down to the entry mapping `addr`, or to the first one which is not present,
+/// so that it can be updated in place (see `memory::demand_zero`).
+///
+/// Returns the entry along with the level of its table, or `None` if the page tables cannot be read yet.
+pub(super) fn last_entry(addr: VirtAddr) -> Option<(&'static mut PageTableEntry, usize)> {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst);
+    if offset == 0 {
+        return None;
+    }
+    let table_mut = |phys: PhysAddr| unsafe { &mut *VirtAddr::new(offset + phys.as_u64()).as_mut_ptr::<PageTable>() };
+    let mut table_ref = table_mut(Cr3::read().0.start_address());
+    for level in (1..=4).rev() {
+        let index = (addr.as_u64() >> (12 + 9 * (level - 1))) as usize & 0x1ff;
+        let entry = &mut table_ref[index];
+        let flags = entry.flags();
+        if level == 1 || !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Some((entry, level));
+        }
+        table_ref = table_mut(entry.addr());
+    }
+    unreachable!("page tables have 4 levels");
+}
+
+/// Calls `f` on every mapped range of the active address space, by increasing address,
+/// contiguous pages with the same flags being merged into a single range.
+pub fn for_each_mapping(mut f: impl FnMut(&Mapping)) {
+    let Some((offset, level_4)) = level_4_table() else {
+        return;
+    };
+    let mut current: Option<Mapping> = None;
+    walk(offset, level_4, 4, 0, SHOWN_FLAGS - PageTableFlags::NO_EXECUTE, &mut |mapping| {
+        match current.as_mut() {
+            Some(run) if run.extends(&mapping) => run.size += mapping.size,
+            _ => {
+                if let Some(run) = current.replace(mapping) {
+                    f(&run);
+                }
+            }
+        }
+    });
+    if let Some(run) = current {
+        f(&run);
+    }
+}
+
+fn walk(
+    offset: u64,
+    table_ref: &PageTable,
+    level: usize,
+    base: u64,
+    flags: PageTableFlags,
+    f: &mut impl FnMut(Mapping),
+) {
+    for (index, entry) in table_ref.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = base | (index as u64) << (12 + 9 * (level - 1));
+        let entry_flags = combine(flags, entry.flags(), level);
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            f(Mapping {
+                start: VirtAddr::new_truncate(start), // Sign extended for the higher half
+                phys: entry.addr(),
+                size: entry_size(level),
+                flags: entry_flags,
+            });
+        } else {
+            walk(offset, table(offset, entry.addr()), level - 1, start, entry_flags, f);
+        }
+    }
+}
+
+/// Prints the mappings of the active address space, one range per line, e.g. `serial_println!("{}", PageTableDump)`.
+pub struct PageTableDump;
+
+impl fmt::Display for PageTableDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut result = Ok(());
+        for_each_mapping(|mapping| {
+            if result.is_ok() {
+                result = writeln!(f, "{}", mapping);
+            }
+        });
+        result
+    }
+}
@@ -1,4 +1,5 @@
 use super::{Task, TaskId};
+use crate::thread;
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
@@ -33,6 +34,17 @@ impl Executor {
         }
     }
 
+    /// Runs the tasks until every one of them completed.
+    pub fn run_until_done(&mut self) {
+        loop {
+            self.run_ready_tasks();
+            if self.tasks.is_empty() {
+                break;
+            }
+            self.sleep_if_idle();
+        }
+    }
+
     fn run_ready_tasks(&mut self) {
         // destructure `self` to avoid borrow checker errors
         let Self {
@@ -62,11 +74,12 @@ impl Executor {
     }
 
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use x86_64::instructions::interrupts;
 
         interrupts::disable();
+        // Checked with interrupts disabled, as a timer interrupt waking a task must not come between this and halting
         if self.task_queue.is_empty() {
-            enable_and_hlt();
+            thread::idle(); // Lets other threads run instead, if any is ready
         } else {
             interrupts::enable();
         }
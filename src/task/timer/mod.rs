@@ -0,0 +1,234 @@
+// Timer futures for async tasks: `sleep`, `interval` and `timeout`.
+// Pending sleeps are kept in a fixed-size lock-free queue along with the waker of their task, which the timer
+// interrupt goes through on each tick where a deadline passed (see `tick`), waking the tasks whose deadline did.
+// Deadlines are thus met at the resolution of a tick of the timer (see `time`), whatever the executor is doing.
+// Interrupt handlers must neither allocate nor free, and dropping a waker may free its task's, so the interrupt
+// moves the sleeps it woke to a second queue, and they are dropped by the next sleep polled or dropped instead.
+// The queues are only changed by tasks with interrupts disabled, so that the interrupt sees them whole.
+// Past `MAX_TIMERS` pending sleeps, the others cannot wait for the interrupt, and have their task polled again
+// right away instead, until a slot frees up or their deadline passes.
+
+use core::future::Future;
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::future::{select, Either};
+use futures_util::stream::Stream;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::time::Instant;
+
+/// Most sleeps which may be in the pending queue at once
+pub const MAX_TIMERS: usize = 256;
+
+struct Timer {
+    deadline: Instant,
+    id: u64, // Telling apart sleeps with the same deadline
+    waker: Waker,
+}
+
+struct Queues {
+    pending: ArrayQueue<Timer>, // Waiting for their deadline, in no particular order
+    expired: ArrayQueue<Timer>, // Woken by the timer interrupt, to be dropped by a task
+}
+
+// Allocated by the first sleep polled, as the timer interrupt cannot
+static QUEUES: OnceCell<Queues> = OnceCell::uninit();
+// Earliest deadline in `pending`, in nanoseconds, so that the interrupt only goes through it once one passed
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn queues() -> &'static Queues {
+    QUEUES.get_or_init(|| Queues {
+        pending: ArrayQueue::new(MAX_TIMERS),
+        expired: ArrayQueue::new(MAX_TIMERS),
+    })
+}
+
+/// Wakes the tasks whose deadline passed. Called by the timer interrupt on each tick.
+pub(crate) fn tick() {
+    let now = Instant::now().as_nanos();
+    if now < NEXT_DEADLINE.load(Ordering::SeqCst) {
+        return;
+    }
+    let Ok(queues) = QUEUES.try_get() else {
+        return;
+    };
+    let mut next = u64::MAX;
+    for _ in 0..queues.pending.len() {
+        let Some(timer) = queues.pending.pop() else {
+            break;
+        };
+        let deadline = timer.deadline.as_nanos();
+        let queue = if deadline <= now {
+            timer.waker.wake_by_ref();
+            &queues.expired
+        } else {
+            next = next.min(deadline);
+            &queues.pending
+        };
+        // Cannot fail, as tasks make sure both queues together hold at most `MAX_TIMERS` sleeps
+        if queue.push(timer).is_err() {
+            unreachable!("timer queues over capacity");
+        }
+    }
+    NEXT_DEADLINE.store(next, Ordering::SeqCst);
+}
+
+/// Number of sleeps waiting for their deadline
+pub fn pending() -> usize {
+    QUEUES.try_get().map_or(0, |queues| queues.pending.len())
+}
+
+/// Takes a sleep out of the pending queue, if there, and drops the expired ones.
+///
+/// Must be called with interrupts disabled.
+fn remove(deadline: Instant, id: u64) {
+    let queues = queues();
+    while queues.expired.pop().is_some() {}
+    for _ in 0..queues.pending.len() {
+        let timer = queues.pending.pop().unwrap();
+        if (timer.deadline, timer.id) != (deadline, id) {
+            let _ = queues.pending.push(timer);
+        }
+    }
+}
+
+/// Adds a sleep to the pending queue, unless it is full.
+///
+/// Returns whether it could. Must be called with interrupts disabled.
+fn insert(timer: Timer) -> bool {
+    let queues = queues();
+    while queues.expired.pop().is_some() {}
+    let deadline = timer.deadline.as_nanos();
+    if queues.pending.push(timer).is_err() {
+        return false;
+    }
+    NEXT_DEADLINE.fetch_min(deadline, Ordering::SeqCst);
+    true
+}
+
+/// Future completing once its deadline passed
+#[must_use = "futures do nothing unless polled"]
+pub struct Sleep {
+    deadline: Instant,
+    id: u64,
+    waker: Option<Waker>, // The one in the pending queue, if it is there
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Removes it from the pending queue, if there.
+    fn unregister(&mut self) {
+        if self.waker.take().is_some() {
+            without_interrupts(|| remove(self.deadline, self.id));
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        // The task may have moved to another waker since the last poll
+        if !self.waker.as_ref().is_some_and(|waker| waker.will_wake(cx.waker())) {
+            self.unregister();
+            let timer = Timer {
+                deadline: self.deadline,
+                id: self.id,
+                waker: cx.waker().clone(),
+            };
+            if without_interrupts(|| insert(timer)) {
+                self.waker = Some(cx.waker().clone());
+            } else {
+                cx.waker().wake_by_ref(); // Tries again on the next poll, the queue being full
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Waits until `deadline`.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        waker: None,
+    }
+}
+
+/// Waits for `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Stream yielding the instant of each period elapsed, the first one a period after its creation.
+/// Periods missed because the task was busy are skipped, so that ticks keep to the same phase.
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    /// Waits for the next tick, and returns its instant.
+    pub async fn tick(&mut self) -> Instant {
+        core::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Instant> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let tick = self.sleep.deadline;
+        let now = Instant::now();
+        let mut next = tick + self.period;
+        while next <= now {
+            next = next + self.period;
+        }
+        self.sleep = sleep_until(next);
+        Poll::Ready(tick)
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        self.get_mut().poll_tick(cx).map(Some)
+    }
+}
+
+/// Ticks every `period`, which must not be zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval with a zero period");
+    Interval { period, sleep: sleep(period) }
+}
+
+/// Error of a future which did not complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs `future` for at most `duration`, dropping it if it did not complete by then.
+pub async fn timeout<F: Future>(future: F, duration: Duration) -> Result<F::Output, Elapsed> {
+    match select(pin!(future), sleep(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
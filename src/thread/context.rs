@@ -0,0 +1,64 @@
+// Context switches between kernel threads. A thread which is switched away from pushes the registers the System V
+// ABI makes callee-saved, along with its flags, on its own stack, and only its stack pointer is kept: switching back
+// pops them and returns where it left. No other register needs saving, as the kernel is built without SSE.
+// New threads get a stack laid out as if they had been switched away from right before entering `entry`.
+
+use core::arch::global_asm;
+
+use x86_64::VirtAddr;
+
+global_asm!(
+    ".global burritos_switch_context",
+    "burritos_switch_context:",
+    "pushfq",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "popfq",
+    "ret",
+);
+
+extern "C" {
+    fn burritos_switch_context(save: *mut u64, next: u64);
+}
+
+const INITIAL_FLAGS: u64 = 0x2; // Reserved bit, interrupts disabled until the thread enables them
+const SAVED_REGISTERS: usize = 6; // rbp, rbx, r12 to r15
+
+/// Saves the context of the running thread, with its stack pointer at `save`, and resumes the thread whose
+/// stack pointer is `next`. Returns once switched back to.
+///
+/// # Safety
+/// Interrupts must be disabled, and `next` must have been saved by a switch or built by `initial_stack`,
+/// and not resumed since.
+pub(super) unsafe fn switch(save: *mut u64, next: u64) {
+    burritos_switch_context(save, next);
+}
+
+/// Lays out the stack ending at `top` for a thread to start in `entry`, and returns its stack pointer.
+///
+/// # Safety
+/// The stack must be mapped, and not in use.
+pub(super) unsafe fn initial_stack(top: VirtAddr, entry: extern "C" fn() -> !) -> u64 {
+    let top = top.align_down(16u64).as_mut_ptr::<u64>();
+    // From the top: a null return address for `entry`, which then starts with a stack aligned as after a call,
+    // where the switch returns, the flags, and the saved registers
+    let frame = [0, entry as usize as u64, INITIAL_FLAGS];
+    let stack = top.sub(frame.len() + SAVED_REGISTERS);
+    stack.write_bytes(0, SAVED_REGISTERS);
+    for (index, value) in frame.into_iter().enumerate() {
+        top.sub(index + 1).write(value);
+    }
+    stack as u64
+}
@@ -0,0 +1,554 @@
+// Preemptive kernel threads, each running on its own guard-paged stack (see `memory::stack`).
+// Which ready thread runs, and for how long, is up to a scheduling policy (see `scheduler`), round-robin at first:
+// the timer interrupt charges the running thread for the time it ran and asks the policy whether it should make way
+// (see `tick`), and once the interrupt ended, switches to the thread the policy picks (see `preempt`) by saving the
+// register context of the current one on its stack (see `context`). Threads may also yield, sleep, or wait for
+// another one to finish, and an idle thread halts the CPU when no other thread is ready.
+//
+// The scheduler is only accessed with interrupts disabled, and never allocates from the timer interrupt, which thus
+// cannot wait for a lock held by the thread it interrupted. Threads holding the heap or the kernel paging structures
+// are not preempted either, so that other threads can always take them, interrupts disabled or not.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::allocator::ALLOCATOR;
+use crate::memory::{KernelStack, VmmError, KERNEL_PAGING};
+use crate::time::{self, Instant};
+
+mod context;
+pub mod scheduler;
+
+use scheduler::{Params, Policy, RoundRobin};
+
+/// Maximum number of threads, the boot and idle ones included
+pub const MAX_THREADS: usize = 64;
+/// Ticks a thread runs for before making way for the ready ones, with the policies which take turns
+pub const QUANTUM: u64 = 10;
+const STACK_PAGES: u64 = 16;
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+// Set by the timer interrupt once the running thread should be preempted
+static NEED_RESCHEDULE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+pub enum ThreadError {
+    NotInitialized, // `init` was not called
+    NoPaging, // The kernel paging structures are not set up
+    TooManyThreads,
+    Vmm(VmmError), // The stack could not be allocated
+    NoSuchThread, // It finished and was freed, or never existed
+    InvalidParams, // Out of range (see `Params::is_valid`)
+}
+
+/// Statistics of the scheduler and its run queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub policy: &'static str,
+    pub threads: usize, // The boot and idle ones included
+    pub ready: usize, // In the run queue
+    pub max_ready: usize, // Most threads ever in the run queue at once
+    pub switches: u64,
+    pub preemptions: u64, // Switches the timer caused
+    pub idle: Duration, // Time the idle thread ran for
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    Running,
+    Sleeping(Instant), // Until the given instant
+    Blocked, // Joining another thread
+    Finished,
+}
+
+struct Thread {
+    state: State,
+    stack_pointer: u64, // Saved while the thread is not running
+    stack: Option<KernelStack>, // `None` for the boot thread, which runs on the bootloader's stack
+    entry: Option<Box<dyn FnOnce() + Send>>, // Taken once the thread starts
+    joiner: Option<ThreadId>, // Thread blocked until this one finishes
+    detached: bool, // Its handle was dropped, so that nothing joins it
+    params: Params,
+    runtime: u64, // In nanoseconds
+}
+
+impl Thread {
+    fn new(stack: Option<KernelStack>, entry: Option<Box<dyn FnOnce() + Send>>, params: Params) -> Self {
+        Thread {
+            state: if stack.is_some() { State::Ready } else { State::Running },
+            stack_pointer: 0,
+            stack,
+            entry,
+            joiner: None,
+            detached: false,
+            params,
+            runtime: 0,
+        }
+    }
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>, // Boxed, so that saved stack pointers do not move
+    policy: Box<dyn Policy>,
+    current: ThreadId,
+    idle: ThreadId,
+    ticks: u64, // Run by the current thread since it was switched to
+    charged: Instant, // Until when the current thread was charged for the time it ran
+    max_ready: usize,
+    switches: u64,
+    preemptions: u64,
+    idle_time: Duration,
+}
+
+impl Scheduler {
+    fn thread(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("unknown thread")
+    }
+
+    fn make_ready(&mut self, id: ThreadId) {
+        self.thread(id).state = State::Ready;
+        if id != self.idle {
+            self.policy.enqueue(id);
+            self.max_ready = self.max_ready.max(self.policy.len());
+        }
+    }
+
+    /// Charges the current thread for the time it ran since last charged.
+    fn charge(&mut self, now: Instant) {
+        let ran = (now - self.charged).as_nanos() as u64;
+        self.charged = now;
+        let current = self.current;
+        self.thread(current).runtime += ran;
+        if current == self.idle {
+            self.idle_time += Duration::from_nanos(ran);
+        } else {
+            self.policy.charge(current, ran);
+        }
+    }
+
+    fn wake_sleepers(&mut self, now: Instant) {
+        let awake = self.threads.iter().filter_map(|(&id, thread)| match thread.state {
+            State::Sleeping(until) if until <= now => Some(id),
+            _ => None,
+        });
+        // Collected without allocating, as this runs in the timer interrupt
+        let mut woken = [None; MAX_THREADS];
+        for (slot, id) in woken.iter_mut().zip(awake) {
+            *slot = Some(id);
+        }
+        for id in woken.into_iter().flatten() {
+            self.make_ready(id);
+        }
+    }
+}
+
+/// Makes the running code the boot thread, and starts the idle thread.
+///
+/// Needs the heap, and the kernel paging structures to allocate stacks (see `allocator::init_heap`).
+pub fn init() -> Result<(), ThreadError> {
+    let (boot, idle) = (ThreadId::new(), ThreadId::new());
+    let mut threads = BTreeMap::new();
+    threads.insert(boot, Box::new(Thread::new(None, None, Params::default())));
+    threads.insert(idle, new_thread(Box::new(idle_loop), Params::default())?);
+    let mut policy = Box::new(RoundRobin::new());
+    policy.add(boot, Params::default());
+    let scheduler = Scheduler {
+        threads,
+        policy,
+        current: boot,
+        idle,
+        ticks: 0,
+        charged: Instant::now(),
+        max_ready: 0,
+        switches: 0,
+        preemptions: 0,
+        idle_time: Duration::ZERO,
+    };
+    without_interrupts(|| *SCHEDULER.lock() = Some(scheduler));
+    Ok(())
+}
+
+/// Runs when no other thread is ready, freeing the finished threads nothing will join meanwhile.
+fn idle_loop() {
+    loop {
+        reap();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Where threads start: runs the entry of the current thread, then ends it.
+extern "C" fn thread_entry() -> ! {
+    let entry = {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().unwrap();
+        let current = scheduler.current;
+        scheduler.thread(current).entry.take()
+    };
+    interrupts::enable();
+    entry.expect("thread started twice")();
+
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().unwrap();
+        let current = scheduler.current;
+        if let Some(joiner) = scheduler.thread(current).joiner.take() {
+            scheduler.make_ready(joiner);
+        }
+    }
+    switch_from_current(State::Finished);
+    unreachable!("finished thread resumed");
+}
+
+/// Moves the current thread to `state`, and switches to the next ready thread, or to the idle one if none is
+/// ready and the current one cannot go on. Returns once switched back to, or right away if no switch was needed.
+///
+/// Interrupts must be disabled.
+fn switch_from_current(state: State) {
+    let (save, next) = {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_mut() else {
+            return;
+        };
+        let now = Instant::now();
+        scheduler.wake_sleepers(now);
+        scheduler.charge(now);
+        let current = scheduler.current;
+        if state == State::Ready {
+            scheduler.make_ready(current);
+        } else {
+            scheduler.thread(current).state = state;
+        }
+        let next = match scheduler.policy.pick_next() {
+            Some(next) => next,
+            None if state == State::Ready => current,
+            None => scheduler.idle,
+        };
+        scheduler.thread(next).state = State::Running;
+        scheduler.ticks = 0;
+        NEED_RESCHEDULE.store(false, Ordering::SeqCst);
+        if next == current {
+            return;
+        }
+        scheduler.switches += 1;
+        scheduler.current = next;
+        let save = &mut scheduler.thread(current).stack_pointer as *mut u64;
+        (save, scheduler.thread(next).stack_pointer)
+    };
+    unsafe { context::switch(save, next) };
+}
+
+/// Counts a tick of the timer, waking the threads whose sleep ended, and asks for the current thread to be
+/// preempted if the policy tells it should make way for a ready one.
+///
+/// Called by the timer interrupt.
+pub(crate) fn tick() {
+    let mut scheduler = SCHEDULER.lock();
+    let Some(scheduler) = scheduler.as_mut() else {
+        return;
+    };
+    let now = Instant::now();
+    scheduler.wake_sleepers(now);
+    scheduler.charge(now);
+    scheduler.ticks += 1;
+    let (current, ticks) = (scheduler.current, scheduler.ticks);
+    let idle = current == scheduler.idle;
+    if !scheduler.policy.is_empty() && (idle || scheduler.policy.should_preempt(current, ticks)) {
+        NEED_RESCHEDULE.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Switches to the next ready thread if the timer asked for it.
+///
+/// Called once an interrupt ended, right before returning from it.
+pub(crate) fn preempt() {
+    if !NEED_RESCHEDULE.load(Ordering::SeqCst) {
+        return;
+    }
+    // Threads holding these are left running (see above); the guard taken to check is dropped right away
+    if ALLOCATOR.is_locked() || KERNEL_PAGING.try_lock().is_none() {
+        return;
+    }
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.preemptions += 1;
+    }
+    switch_from_current(State::Ready);
+}
+
+/// Lets the other ready threads run before going on.
+pub fn yield_now() {
+    without_interrupts(|| switch_from_current(State::Ready));
+}
+
+/// Halts the CPU until the next interrupt, unless other threads are ready, in which case they run meanwhile.
+///
+/// Must be called with interrupts disabled, which it enables, so that an interrupt cannot come right before halting.
+pub fn idle() {
+    let others_ready = SCHEDULER.lock().as_ref().is_some_and(|scheduler| !scheduler.policy.is_empty());
+    if others_ready {
+        switch_from_current(State::Ready);
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Blocks the current thread for `duration`, other threads running meanwhile.
+pub fn sleep(duration: Duration) {
+    let until = time::now() + duration;
+    if current().is_none() {
+        while time::now() < until {
+            x86_64::instructions::hlt();
+        }
+        return;
+    }
+    while time::now() < until {
+        without_interrupts(|| switch_from_current(State::Sleeping(until)));
+    }
+}
+
+/// Id of the running thread, `None` before `init`
+pub fn current() -> Option<ThreadId> {
+    without_interrupts(|| SCHEDULER.lock().as_ref().map(|scheduler| scheduler.current))
+}
+
+/// Runs `f` on the thread `id`, if it exists.
+fn with_thread<R>(id: ThreadId, f: impl FnOnce(&mut Scheduler) -> R) -> Result<R, ThreadError> {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().ok_or(ThreadError::NotInitialized)?;
+        if !scheduler.threads.contains_key(&id) || id == scheduler.idle {
+            return Err(ThreadError::NoSuchThread);
+        }
+        Ok(f(scheduler))
+    })
+}
+
+/// What the thread `id` asks of the scheduler
+pub fn params(id: ThreadId) -> Result<Params, ThreadError> {
+    with_thread(id, |scheduler| scheduler.thread(id).params)
+}
+
+/// Changes what the thread `id` asks of the scheduler, which takes it into account from the next tick on.
+pub fn set_params(id: ThreadId, params: Params) -> Result<(), ThreadError> {
+    if !params.is_valid() {
+        return Err(ThreadError::InvalidParams);
+    }
+    with_thread(id, |scheduler| {
+        scheduler.thread(id).params = params;
+        scheduler.policy.set_params(id, params);
+    })
+}
+
+/// Time the thread `id` ran for
+pub fn runtime(id: ThreadId) -> Result<Duration, ThreadError> {
+    with_thread(id, |scheduler| {
+        if id == scheduler.current {
+            scheduler.charge(Instant::now());
+        }
+        Duration::from_nanos(scheduler.thread(id).runtime)
+    })
+}
+
+/// Replaces the scheduling policy, the ready threads moving over to the new one in the order the old one would
+/// have run them.
+pub fn set_policy(mut policy: Box<dyn Policy>) -> Result<(), ThreadError> {
+    let old = without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().ok_or(ThreadError::NotInitialized)?;
+        for (&id, thread) in &scheduler.threads {
+            if id != scheduler.idle {
+                policy.add(id, thread.params);
+            }
+        }
+        while let Some(id) = scheduler.policy.pick_next() {
+            policy.enqueue(id);
+        }
+        Ok(core::mem::replace(&mut scheduler.policy, policy))
+    })?;
+    drop(old);
+    Ok(())
+}
+
+/// Statistics of the scheduler, `None` before `init`
+pub fn stats() -> Option<Stats> {
+    without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_ref()?;
+        Some(Stats {
+            policy: scheduler.policy.name(),
+            threads: scheduler.threads.len(),
+            ready: scheduler.policy.len(),
+            max_ready: scheduler.max_ready,
+            switches: scheduler.switches,
+            preemptions: scheduler.preemptions,
+            idle: scheduler.idle_time,
+        })
+    })
+}
+
+/// Frees the stacks of the finished threads which nothing will join.
+fn reap() {
+    loop {
+        let thread = without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut()?;
+            let (&id, _) = scheduler
+                .threads
+                .iter()
+                .find(|(_, thread)| thread.state == State::Finished && thread.detached)?;
+            scheduler.policy.remove(id);
+            scheduler.threads.remove(&id)
+        });
+        match thread {
+            Some(mut thread) => free(&mut thread),
+            None => break,
+        }
+    }
+}
+
+/// Frees the stack of a finished thread.
+fn free(thread: &mut Thread) {
+    if let Some(stack) = thread.stack.take() {
+        let mut paging = KERNEL_PAGING.lock();
+        paging.as_mut().unwrap().free_stack(stack).expect("thread stack freed twice");
+    }
+}
+
+/// Returns a thread about to run `entry`, on a new stack.
+fn new_thread(entry: Box<dyn FnOnce() + Send>, params: Params) -> Result<Box<Thread>, ThreadError> {
+    let stack = {
+        let mut paging = KERNEL_PAGING.lock();
+        let paging = paging.as_mut().ok_or(ThreadError::NoPaging)?;
+        paging.allocate_stack(STACK_PAGES).map_err(ThreadError::Vmm)?
+    };
+    let top = stack.top();
+    let mut thread = Box::new(Thread::new(Some(stack), Some(entry), params));
+    thread.stack_pointer = unsafe { context::initial_stack(top, thread_entry) };
+    Ok(thread)
+}
+
+/// Adds a thread running `entry` to the ready ones.
+fn spawn_thread(entry: Box<dyn FnOnce() + Send>, params: Params) -> Result<ThreadId, ThreadError> {
+    if !params.is_valid() {
+        return Err(ThreadError::InvalidParams);
+    }
+    reap();
+    let thread = new_thread(entry, params)?;
+    let id = ThreadId::new();
+    let refused = without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_mut() else {
+            return Some((thread, ThreadError::NotInitialized));
+        };
+        if scheduler.threads.len() >= MAX_THREADS {
+            return Some((thread, ThreadError::TooManyThreads));
+        }
+        scheduler.threads.insert(id, thread);
+        scheduler.policy.add(id, params);
+        scheduler.make_ready(id);
+        None
+    });
+    match refused {
+        Some((mut thread, err)) => {
+            free(&mut thread);
+            Err(err)
+        }
+        None => Ok(id),
+    }
+}
+
+/// Handle to wait for a thread to finish, and get what it returned. Dropping it detaches the thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+/// Starts a thread running `f`.
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, ThreadError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_with(Params::default(), f)
+}
+
+/// Starts a thread running `f`, scheduled as `params` asks.
+pub fn spawn_with<F, T>(params: Params, f: F) -> Result<JoinHandle<T>, ThreadError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let packet = result.clone();
+    let entry = Box::new(move || {
+        let output = f();
+        *packet.lock() = Some(output);
+    });
+    let id = spawn_thread(entry, params)?;
+    Ok(JoinHandle { id, result })
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            scheduler.as_mut().unwrap().thread(self.id).state == State::Finished
+        })
+    }
+
+    /// Blocks until the thread finished, and returns what it returned.
+    pub fn join(self) -> T {
+        assert_ne!(current(), Some(self.id), "thread joining itself");
+        without_interrupts(|| loop {
+            let finished = {
+                let mut scheduler = SCHEDULER.lock();
+                let scheduler = scheduler.as_mut().unwrap();
+                let current = scheduler.current;
+                let thread = scheduler.thread(self.id);
+                thread.joiner = Some(current);
+                thread.state == State::Finished
+            };
+            if finished {
+                break;
+            }
+            switch_from_current(State::Blocked);
+        });
+        let result = self.result.lock().take();
+        result.expect("thread finished without a result")
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    /// Only marks the thread detached: it is freed once finished, by the next spawn or the idle thread, so that
+    /// dropping a handle neither takes the kernel paging structures nor frees memory.
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            if let Some(thread) = scheduler.as_mut().and_then(|scheduler| scheduler.threads.get_mut(&self.id)) {
+                thread.detached = true;
+            }
+        });
+    }
+}
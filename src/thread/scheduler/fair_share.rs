@@ -0,0 +1,126 @@
+// Fair-share policy, after Linux's CFS: each thread accrues a virtual runtime, the time it ran for scaled down by
+// its weight, and the ready thread which ran the least runs next. Weights follow nice values, each step of which
+// makes a thread get about 10% more or less of the CPU than another one, as with Linux.
+// The running thread is preempted once it is `GRANULARITY` of virtual runtime ahead of the least ready one, and
+// threads becoming ready start no further behind than the least virtual runtime, so that a thread which slept for
+// long cannot then take the CPU over until it caught up.
+
+use alloc::collections::BTreeMap;
+
+use super::{Params, Policy, NICE_RANGE};
+use crate::thread::ThreadId;
+
+const NICE_0_WEIGHT: u64 = 1024;
+const GRANULARITY: u64 = 3_000_000; // In nanoseconds, of virtual runtime
+
+// From nice -20 to 19, as in Linux's `sched_prio_to_weight`
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904, 3906, 3121, 2501,
+    1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87, 70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+fn weight(nice: i8) -> u64 {
+    let nice = nice.clamp(*NICE_RANGE.start(), *NICE_RANGE.end());
+    WEIGHTS[(nice - NICE_RANGE.start()) as usize]
+}
+
+struct Entity {
+    weight: u64,
+    vruntime: u64, // In nanoseconds, as if run with the weight of nice 0
+    queued: bool,
+}
+
+pub struct FairShare {
+    threads: BTreeMap<ThreadId, Entity>,
+    ready: usize,
+    min_vruntime: u64, // Least virtual runtime of the running and ready threads, never going back
+}
+
+impl FairShare {
+    pub fn new() -> Self {
+        FairShare {
+            threads: BTreeMap::new(),
+            ready: 0,
+            min_vruntime: 0,
+        }
+    }
+
+    /// Ready thread with the least virtual runtime, along with it
+    fn least(&self) -> Option<(u64, ThreadId)> {
+        self.threads
+            .iter()
+            .filter(|(_, entity)| entity.queued)
+            .map(|(&id, entity)| (entity.vruntime, id))
+            .min()
+    }
+}
+
+impl Default for FairShare {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Policy for FairShare {
+    fn name(&self) -> &'static str {
+        "fair-share"
+    }
+
+    fn add(&mut self, thread: ThreadId, params: Params) {
+        let entity = Entity {
+            weight: weight(params.nice),
+            vruntime: self.min_vruntime,
+            queued: false,
+        };
+        self.threads.insert(thread, entity);
+    }
+
+    fn remove(&mut self, thread: ThreadId) {
+        if let Some(Entity { queued: true, .. }) = self.threads.remove(&thread) {
+            self.ready -= 1;
+        }
+    }
+
+    fn set_params(&mut self, thread: ThreadId, params: Params) {
+        if let Some(entity) = self.threads.get_mut(&thread) {
+            entity.weight = weight(params.nice);
+        }
+    }
+
+    fn enqueue(&mut self, thread: ThreadId) {
+        let entity = self.threads.get_mut(&thread).expect("thread enqueued before being added");
+        if !entity.queued {
+            entity.vruntime = entity.vruntime.max(self.min_vruntime);
+            entity.queued = true;
+            self.ready += 1;
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let (_, thread) = self.least()?;
+        self.threads.get_mut(&thread).unwrap().queued = false;
+        self.ready -= 1;
+        Some(thread)
+    }
+
+    fn charge(&mut self, thread: ThreadId, nanos: u64) {
+        let Some(entity) = self.threads.get_mut(&thread) else {
+            return;
+        };
+        entity.vruntime += nanos * NICE_0_WEIGHT / entity.weight;
+        let vruntime = entity.vruntime;
+        let least = self.least().map_or(vruntime, |(least, _)| least.min(vruntime));
+        self.min_vruntime = self.min_vruntime.max(least);
+    }
+
+    fn should_preempt(&self, current: ThreadId, _ticks: u64) -> bool {
+        let Some(current) = self.threads.get(&current) else {
+            return true;
+        };
+        self.least().is_some_and(|(least, _)| current.vruntime > least + GRANULARITY)
+    }
+
+    fn len(&self) -> usize {
+        self.ready
+    }
+}
@@ -0,0 +1,80 @@
+// Scheduling policies, which decide which ready thread runs next and when the running one is preempted.
+// The thread module keeps the threads and switches between them; a policy only keeps its run queue, and whatever it
+// needs to know about each thread, e.g. its priority or the time it ran for. Policies can be swapped at runtime
+// (see `thread::set_policy`), the ready threads then moving over to the new run queue in the order the old one
+// would have run them.
+//
+// `enqueue`, `pick_next`, `charge`, `should_preempt` and `len` are called from the timer interrupt, so they must
+// not allocate: whatever a thread needs is allocated when it is added.
+
+use super::ThreadId;
+
+mod fair_share;
+mod priority;
+mod round_robin;
+
+pub use fair_share::FairShare;
+pub use priority::Priority;
+pub use round_robin::RoundRobin;
+
+/// Highest priority, which runs before any other
+pub const MAX_PRIORITY: u8 = 31;
+pub const DEFAULT_PRIORITY: u8 = 16;
+/// Range of nice values, from the largest share of the CPU to the smallest
+pub const NICE_RANGE: core::ops::RangeInclusive<i8> = -20..=19;
+
+/// What a thread asks of the scheduler, each policy using what it understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    pub priority: u8, // Up to `MAX_PRIORITY`, for `Priority`
+    pub nice: i8, // Within `NICE_RANGE`, for `FairShare`
+}
+
+impl Params {
+    pub fn is_valid(&self) -> bool {
+        self.priority <= MAX_PRIORITY && NICE_RANGE.contains(&self.nice)
+    }
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            priority: DEFAULT_PRIORITY,
+            nice: 0,
+        }
+    }
+}
+
+/// A scheduling policy, holding the run queue of the ready threads. The idle thread is never given to it.
+pub trait Policy: Send {
+    fn name(&self) -> &'static str;
+
+    /// Starts keeping track of a thread, which is not ready yet.
+    fn add(&mut self, thread: ThreadId, params: Params);
+
+    /// Stops keeping track of a finished thread.
+    fn remove(&mut self, thread: ThreadId);
+
+    /// Changes what a thread asks for, whether it is ready or not.
+    fn set_params(&mut self, thread: ThreadId, params: Params);
+
+    /// Adds a thread to the run queue.
+    fn enqueue(&mut self, thread: ThreadId);
+
+    /// Takes the thread to run next out of the run queue.
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    /// Accounts for `nanos` nanoseconds the thread ran for.
+    fn charge(&mut self, _thread: ThreadId, _nanos: u64) {}
+
+    /// Whether the running thread, switched to `ticks` timer ticks ago, should make way for the ready ones.
+    /// Only asked while the run queue is not empty.
+    fn should_preempt(&self, current: ThreadId, ticks: u64) -> bool;
+
+    /// Number of threads in the run queue
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
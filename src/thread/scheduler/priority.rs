@@ -0,0 +1,100 @@
+// Fixed-priority policy: the ready thread with the highest priority runs, and preempts any thread of a lower one
+// at the next tick. Threads of the same priority take turns as with round-robin, and lower ones wait, possibly
+// forever, for the higher ones to sleep, block or finish.
+
+use alloc::collections::BTreeMap;
+use core::cmp::Reverse;
+
+use super::{Params, Policy};
+use crate::thread::{ThreadId, QUANTUM};
+
+struct Entry {
+    priority: u8,
+    queued: Option<u64>, // Order it was enqueued in, while in the run queue
+}
+
+pub struct Priority {
+    threads: BTreeMap<ThreadId, Entry>,
+    ready: usize,
+    next_order: u64,
+}
+
+impl Priority {
+    pub fn new() -> Self {
+        Priority {
+            threads: BTreeMap::new(),
+            ready: 0,
+            next_order: 0,
+        }
+    }
+
+    fn priority(&self, thread: ThreadId) -> u8 {
+        self.threads.get(&thread).map_or(0, |entry| entry.priority)
+    }
+
+    /// Highest priority in the run queue, along with the thread enqueued first with it
+    fn highest(&self) -> Option<(u8, ThreadId)> {
+        self.threads
+            .iter()
+            .filter_map(|(&id, entry)| Some((entry.priority, Reverse(entry.queued?), id)))
+            .max()
+            .map(|(priority, _, id)| (priority, id))
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Policy for Priority {
+    fn name(&self) -> &'static str {
+        "priority"
+    }
+
+    fn add(&mut self, thread: ThreadId, params: Params) {
+        let entry = Entry {
+            priority: params.priority,
+            queued: None,
+        };
+        self.threads.insert(thread, entry);
+    }
+
+    fn remove(&mut self, thread: ThreadId) {
+        if let Some(Entry { queued: Some(_), .. }) = self.threads.remove(&thread) {
+            self.ready -= 1;
+        }
+    }
+
+    fn set_params(&mut self, thread: ThreadId, params: Params) {
+        if let Some(entry) = self.threads.get_mut(&thread) {
+            entry.priority = params.priority;
+        }
+    }
+
+    fn enqueue(&mut self, thread: ThreadId) {
+        let entry = self.threads.get_mut(&thread).expect("thread enqueued before being added");
+        if entry.queued.is_none() {
+            entry.queued = Some(self.next_order);
+            self.next_order += 1;
+            self.ready += 1;
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let (_, thread) = self.highest()?;
+        self.threads.get_mut(&thread).unwrap().queued = None;
+        self.ready -= 1;
+        Some(thread)
+    }
+
+    fn should_preempt(&self, current: ThreadId, ticks: u64) -> bool {
+        let current = self.priority(current);
+        self.highest().is_some_and(|(highest, _)| highest > current || (highest == current && ticks >= QUANTUM))
+    }
+
+    fn len(&self) -> usize {
+        self.ready
+    }
+}
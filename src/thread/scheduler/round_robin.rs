@@ -0,0 +1,54 @@
+// Round-robin policy: ready threads run in the order they became ready, for `QUANTUM` ticks each.
+
+use alloc::collections::VecDeque;
+
+use super::{Params, Policy};
+use crate::thread::{ThreadId, MAX_THREADS, QUANTUM};
+
+pub struct RoundRobin {
+    ready: VecDeque<ThreadId>, // Allocated for `MAX_THREADS` up front
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        RoundRobin {
+            ready: VecDeque::with_capacity(MAX_THREADS),
+        }
+    }
+}
+
+impl Default for RoundRobin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Policy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+
+    fn add(&mut self, _thread: ThreadId, _params: Params) {}
+
+    fn remove(&mut self, thread: ThreadId) {
+        self.ready.retain(|&id| id != thread);
+    }
+
+    fn set_params(&mut self, _thread: ThreadId, _params: Params) {}
+
+    fn enqueue(&mut self, thread: ThreadId) {
+        self.ready.push_back(thread);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.ready.pop_front()
+    }
+
+    fn should_preempt(&self, _current: ThreadId, ticks: u64) -> bool {
+        ticks >= QUANTUM
+    }
+
+    fn len(&self) -> usize {
+        self.ready.len()
+    }
+}
@@ -0,0 +1,267 @@
+// Kernel time: a tick counter and a monotonic nanosecond clock, both advanced by the timer interrupt.
+// The PIT ticks first (see `init`), then the local APIC timer once the APICs are enabled (see `use_apic_timer`),
+// calibrated against the PIT. Each tick advances the clock by the actual period of the timer, which may slightly
+// differ from the configured frequency's, so that the clock keeps to real time at the resolution of a tick.
+// The wall clock is anchored to the date of the RTC (see `rtc`) once, and then follows that clock.
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::interrupts::apic;
+use crate::interrupts::irq::{self, Handler, HandlerId, IrqError};
+use crate::task::timer;
+use crate::thread;
+
+pub mod pit;
+pub mod rtc;
+
+use rtc::{DateTime, RtcError};
+
+/// Frequency the timer is first set to, in Hz
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+const PIT_LINE: u8 = 0;
+const CALIBRATION: Duration = Duration::from_millis(50); // Time the APIC timer is counted against the PIT
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static NANOS: AtomicU64 = AtomicU64::new(0);
+static PERIOD: AtomicU64 = AtomicU64::new(0); // Of a tick, in nanoseconds
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+static APIC_TIMER_RATE: AtomicU64 = AtomicU64::new(0); // At which it counts down, in Hz, once calibrated
+static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0); // Unix time when the clock was 0, 0 until known
+
+// Timer which ticks, along with the handler of its interrupt
+static SOURCE: Mutex<Option<(Source, HandlerId)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Pit,
+    ApicTimer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeError {
+    InvalidFrequency, // Out of the range of the timer
+    NotStarted, // `init` was not called
+    NoApic,
+    Irq(IrqError),
+}
+
+/// A point in time, as the nanoseconds elapsed since the timer started ticking
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(NANOS.load(Ordering::SeqCst))
+    }
+
+    pub fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the time elapsed from `earlier` to this instant, zero if `earlier` is later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding a duration to an instant")
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+/// Time elapsed since the timer started ticking
+pub fn uptime() -> Duration {
+    Duration::from_nanos(NANOS.load(Ordering::SeqCst))
+}
+
+/// Number of timer interrupts so far
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Frequency the timer is set to, in Hz, 0 before `init`
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::SeqCst)
+}
+
+/// Timer which ticks, if any
+pub fn source() -> Option<Source> {
+    SOURCE.lock().map(|(source, _)| source)
+}
+
+/// Anchors the wall clock to the date and time of the RTC, and returns them.
+pub fn sync_wall_clock() -> Result<DateTime, RtcError> {
+    let date = rtc::read()?;
+    let unix = date.to_unix().ok_or(RtcError::InvalidDate)?;
+    let boot = (unix * NANOS_PER_SECOND).saturating_sub(NANOS.load(Ordering::SeqCst));
+    BOOT_UNIX_NANOS.store(boot, Ordering::SeqCst);
+    Ok(date)
+}
+
+/// Time elapsed since the Unix epoch, `None` until the wall clock is synced
+pub fn unix_time() -> Option<Duration> {
+    let boot = BOOT_UNIX_NANOS.load(Ordering::SeqCst);
+    (boot != 0).then(|| Duration::from_nanos(boot + NANOS.load(Ordering::SeqCst)))
+}
+
+/// Date and time, `None` until the wall clock is synced
+pub fn wall_clock() -> Option<DateTime> {
+    unix_time().map(|time| DateTime::from_unix(time.as_secs()))
+}
+
+fn tick(_context: usize) {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    NANOS.fetch_add(PERIOD.load(Ordering::SeqCst), Ordering::SeqCst);
+    timer::tick();
+    thread::tick();
+}
+
+/// Makes the PIT tick at `frequency`, in Hz, and syncs the wall clock.
+///
+/// Must be called once the PICs are set up (see `interrupts::init_irqs`).
+pub fn init(frequency: u32) -> Result<(), TimeError> {
+    let mut source = SOURCE.lock();
+    if source.is_none() {
+        let handler = irq::register_irq(PIT_LINE, Handler::Fn(tick, 0)).map_err(TimeError::Irq)?;
+        *source = Some((Source::Pit, handler));
+    }
+    drop(source);
+    set_frequency(frequency)?;
+    let _ = sync_wall_clock(); // Left unknown if the RTC holds no valid date
+    Ok(())
+}
+
+/// Sets the rate of the timer which ticks, in Hz. It must be within the range of the PIT (19 Hz to about 600 kHz),
+/// even for the APIC timer.
+pub fn set_frequency(frequency: u32) -> Result<(), TimeError> {
+    let divisor = pit::divisor(frequency).ok_or(TimeError::InvalidFrequency)?;
+    let source = SOURCE.lock();
+    match *source {
+        None => return Err(TimeError::NotStarted),
+        Some((Source::Pit, _)) => {
+            PERIOD.store(pit::cycles_to_nanos(u64::from(divisor)), Ordering::SeqCst);
+            pit::start_periodic(divisor);
+        }
+        Some((Source::ApicTimer, _)) => {
+            let rate = APIC_TIMER_RATE.load(Ordering::SeqCst);
+            let count = (rate + u64::from(frequency) / 2) / u64::from(frequency);
+            let count = u32::try_from(count).ok().filter(|&count| count > 0).ok_or(TimeError::InvalidFrequency)?;
+            PERIOD.store(u64::from(count) * NANOS_PER_SECOND / rate, Ordering::SeqCst);
+            apic::start_timer(count, true);
+        }
+    }
+    FREQUENCY.store(frequency, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Moves the tick over to the local APIC timer, at the same frequency, and stops the PIT.
+///
+/// The APICs must be enabled (see `apic::init`).
+pub fn use_apic_timer() -> Result<(), TimeError> {
+    if !apic::is_enabled() {
+        return Err(TimeError::NoApic);
+    }
+    let mut source = SOURCE.lock();
+    let pit_handler = match *source {
+        None => return Err(TimeError::NotStarted),
+        Some((Source::ApicTimer, _)) => return Ok(()),
+        Some((Source::Pit, handler)) => handler,
+    };
+
+    apic::start_timer(u32::MAX, false);
+    pit::busy_wait(CALIBRATION);
+    let counted = u64::from(u32::MAX - apic::timer_count());
+    apic::start_timer(0, false);
+    APIC_TIMER_RATE.store(counted * NANOS_PER_SECOND / CALIBRATION.as_nanos() as u64, Ordering::SeqCst);
+
+    let handler = irq::register(apic::TIMER_VECTOR, Handler::Fn(tick, 0)).map_err(TimeError::Irq)?;
+    irq::unregister(pit_handler).map_err(TimeError::Irq)?;
+    pit::stop();
+    *source = Some((Source::ApicTimer, handler));
+    drop(source);
+    set_frequency(frequency())
+}
+
+/// Returns the number of ticks while busy waiting for `duration`.
+#[cfg(test)]
+fn ticks_during(duration: Duration) -> u64 {
+    let start = ticks();
+    pit::busy_wait(duration);
+    ticks() - start
+}
+
+#[test_case]
+fn clock_is_monotonic() {
+    let start = now();
+    let mut last = start;
+    for _ in 0..10_000 {
+        let current = now();
+        assert!(current >= last, "clock went back from {:?} to {:?}", last, current);
+        last = current;
+    }
+    pit::busy_wait(Duration::from_millis(10));
+    assert!(now() > start);
+    assert!(uptime() >= Duration::from_nanos(last.as_nanos()));
+}
+
+#[test_case]
+fn ticks_follow_the_frequency() {
+    let start = now();
+    let ticks = ticks_during(Duration::from_millis(200));
+    let expected = u64::from(frequency()) / 5;
+    assert!(ticks > expected * 3 / 4 && ticks < expected * 5 / 4, "{} ticks instead of {}", ticks, expected);
+    let elapsed = start.elapsed();
+    assert!(elapsed > Duration::from_millis(150) && elapsed < Duration::from_millis(250), "{:?}", elapsed);
+}
+
+#[test_case]
+fn wall_clock_follows_the_timer() {
+    let date = sync_wall_clock().unwrap();
+    let start = unix_time().unwrap();
+    assert_eq!(Some(start.as_secs()), date.to_unix());
+    pit::busy_wait(Duration::from_millis(100));
+    let elapsed = unix_time().unwrap() - start;
+    assert!(elapsed > Duration::from_millis(90) && elapsed < Duration::from_millis(110), "{:?}", elapsed);
+    assert!(wall_clock().unwrap() >= date);
+}
+
+#[test_case]
+fn frequency_can_be_changed() {
+    assert_eq!(set_frequency(0), Err(TimeError::InvalidFrequency));
+    assert_eq!(set_frequency(10), Err(TimeError::InvalidFrequency)); // Below what the PIT can divide down to
+
+    set_frequency(250).unwrap();
+    let ticks = ticks_during(Duration::from_millis(200));
+    set_frequency(DEFAULT_FREQUENCY).unwrap();
+    assert!(ticks > 50 * 3 / 4 && ticks < 50 * 5 / 4, "{} ticks instead of 50", ticks);
+}
@@ -0,0 +1,87 @@
+// Programmable interval timer (8253/8254): three counters decremented at 1.193182 MHz. Counter 0 raises IRQ 0 each
+// time it wraps, which makes the kernel tick until the local APIC timer takes over (see `time`), while counter 2,
+// whose output can be polled, serves to wait for a given duration without interrupts, e.g. to calibrate other timers.
+
+use core::hint::spin_loop;
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Rate at which the counters are decremented, in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const CONTROL: u16 = 0x61; // Gate of counter 2 (bit 0), speaker (bit 1), and output of counter 2 (bit 5)
+
+// Commands: counter (bits 6-7), access to the count (bits 4-5), then mode (bits 1-3), counting in binary
+const SELECT_CHANNEL_2: u8 = 0b10 << 6;
+const LOW_THEN_HIGH_BYTE: u8 = 0b11 << 4;
+const ONE_SHOT: u8 = 0; // Mode 0: the output goes high once the count is reached
+const RATE_GENERATOR: u8 = 2 << 1; // Mode 2: counter 0 raises IRQ 0 each time the count is reached, and starts over
+
+const GATE: u8 = 1;
+const SPEAKER: u8 = 1 << 1;
+const OUTPUT: u8 = 1 << 5;
+
+// Counter 2 is shared by every waiting caller
+static COUNTER_2: Mutex<()> = Mutex::new(());
+
+/// Returns the divisor of `FREQUENCY` closest to `frequency`, if the counters can be set to it.
+pub fn divisor(frequency: u32) -> Option<u16> {
+    let frequency = u64::from(frequency);
+    if frequency == 0 {
+        return None;
+    }
+    let divisor = (FREQUENCY + frequency / 2) / frequency;
+    u16::try_from(divisor).ok().filter(|&divisor| divisor > 1)
+}
+
+/// Returns the nanoseconds `cycles` cycles of the counters last.
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    (u128::from(cycles) * 1_000_000_000 / u128::from(FREQUENCY)) as u64
+}
+
+fn write_count(port: u16, count: u16) {
+    let mut data = Port::<u8>::new(port);
+    let [low, high] = count.to_le_bytes();
+    unsafe {
+        data.write(low);
+        data.write(high);
+    }
+}
+
+/// Makes counter 0 raise IRQ 0 every `divisor` cycles.
+pub(super) fn start_periodic(divisor: u16) {
+    unsafe { Port::<u8>::new(COMMAND).write(LOW_THEN_HIGH_BYTE | RATE_GENERATOR) };
+    write_count(CHANNEL_0, divisor);
+}
+
+/// Stops counter 0, which then waits for a count that never comes.
+pub(super) fn stop() {
+    unsafe { Port::<u8>::new(COMMAND).write(LOW_THEN_HIGH_BYTE | ONE_SHOT) };
+}
+
+/// Waits for `duration` by polling counter 2, which works with interrupts disabled.
+pub fn busy_wait(duration: Duration) {
+    let _counter = COUNTER_2.lock();
+    let mut control = Port::<u8>::new(CONTROL);
+    let mut cycles = (duration.as_nanos() * u128::from(FREQUENCY)).div_ceil(1_000_000_000) as u64;
+    while cycles > 0 {
+        let count = cycles.min(u64::from(u16::MAX)) as u16;
+        unsafe {
+            // Counting is paused while the gate is low, and starts once the count is written and the gate raised
+            let value = control.read() & !(GATE | SPEAKER);
+            control.write(value);
+            Port::<u8>::new(COMMAND).write(SELECT_CHANNEL_2 | LOW_THEN_HIGH_BYTE | ONE_SHOT);
+            write_count(CHANNEL_2, count);
+            control.write(value | GATE);
+            while control.read() & OUTPUT == 0 {
+                spin_loop();
+            }
+        }
+        cycles -= u64::from(count);
+    }
+}
@@ -0,0 +1,276 @@
+// CMOS real-time clock: the battery-backed clock of the PC, which keeps the date and time (UTC on QEMU) while powered
+// off, at the resolution of a second. Its registers are read through the CMOS index and data ports, in BCD or binary
+// and with hours in 12 or 24 hour mode as register B tells, and must not be read while the RTC updates them: reads
+// wait for any update to end, and are retried until two in a row agree.
+// The RTC can also raise IRQ 8 periodically, at a power of two from 2 Hz to 8 kHz (see `start_periodic`).
+
+use core::fmt;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::irq::{self, Handler, HandlerId, IrqError};
+
+/// ISA line of the RTC interrupt
+pub const LINE: u8 = 8;
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c; // Reading it acknowledges the interrupt
+const CENTURY: u8 = 0x32; // Where QEMU and most BIOSes keep it
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7; // Of status A, whose low bits are the rate of periodic interrupts
+const RATE: u8 = 0x0f;
+const HOURS_24: u8 = 1 << 1; // Of status B
+const BINARY: u8 = 1 << 2;
+const PERIODIC_ENABLE: u8 = 1 << 6;
+const PM: u8 = 1 << 7; // Of the hours, in 12 hour mode
+
+const BASE_FREQUENCY: u32 = 32768; // Of the oscillator, which periodic interrupts divide
+const SECONDS_PER_DAY: u64 = 86400;
+
+// Held along with the index register, as it selects what the data port accesses
+static CMOS: Mutex<()> = Mutex::new(());
+static PERIODIC: Mutex<Option<HandlerId>> = Mutex::new(None);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    InvalidDate, // The RTC holds no valid date, or one before the Unix epoch
+    InvalidFrequency,
+    Irq(IrqError),
+}
+
+/// Date and time of the day, in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8, // From 1
+    pub day: u8, // From 1
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    /// Returns the given date and time, provided that they exist.
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Option<DateTime> {
+        let valid = (1..=12).contains(&month)
+            && (1..=days_in_month(year, month)).contains(&day)
+            && hour < 24
+            && minute < 60
+            && second < 60;
+        valid.then_some(DateTime { year, month, day, hour, minute, second })
+    }
+
+    /// Returns the date and time `seconds` seconds after the Unix epoch.
+    pub fn from_unix(seconds: u64) -> DateTime {
+        // Days are counted in 400-year eras starting on the 1st of March, so that leap days end the years
+        let days = seconds / SECONDS_PER_DAY + 719_468; // From 0000-03-01
+        let (era, day_of_era) = (days / 146_097, days % 146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+        let time = seconds % SECONDS_PER_DAY;
+        DateTime {
+            year: (era * 400 + year_of_era + u64::from(month <= 2)) as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Returns the seconds elapsed since the Unix epoch, `None` if before it.
+    pub fn to_unix(&self) -> Option<u64> {
+        let year = u64::from(self.year).checked_sub(u64::from(self.month <= 2))?;
+        let (era, year_of_era) = (year / 400, year % 400);
+        let month_from_march = (u64::from(self.month) + 9) % 12;
+        let day_of_year = (153 * month_from_march + 2) / 5 + u64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+        let time = u64::from(self.hour) * 3600 + u64::from(self.minute) * 60 + u64::from(self.second);
+        Some(days * SECONDS_PER_DAY + time)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Runs `f` with exclusive access to the CMOS, interrupt handlers included.
+fn with_cmos<R>(f: impl FnOnce() -> R) -> R {
+    without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        f()
+    })
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::new(INDEX).write(register);
+        Port::new(DATA).read()
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::new(INDEX).write(register);
+        Port::new(DATA).write(value);
+    }
+}
+
+/// Reads the date registers once no update is in progress.
+fn read_date_registers() -> [u8; 7] {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        spin_loop();
+    }
+    [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR, CENTURY].map(read_register)
+}
+
+/// Reads the date and time of the RTC.
+pub fn read() -> Result<DateTime, RtcError> {
+    let (registers, status_b) = with_cmos(|| {
+        let mut registers = read_date_registers();
+        loop {
+            let again = read_date_registers();
+            if again == registers {
+                break (registers, read_register(STATUS_B));
+            }
+            registers = again;
+        }
+    });
+
+    let decode = |value: u8| if status_b & BINARY != 0 { value } else { (value >> 4) * 10 + (value & 0x0f) };
+    let [seconds, minutes, hours, day, month, year, century] = registers;
+    let mut hour = decode(hours & !PM);
+    if status_b & HOURS_24 == 0 {
+        hour %= 12; // 12 AM is midnight
+        if hours & PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = match decode(century) {
+        century @ 19..=99 => u16::from(century),
+        _ => 20, // Not kept by this RTC
+    };
+    let year = century * 100 + u16::from(decode(year));
+    DateTime::new(year, decode(month), decode(day), hour, decode(minutes), decode(seconds)).ok_or(RtcError::InvalidDate)
+}
+
+fn periodic_interrupt(_context: usize) {
+    with_cmos(|| read_register(STATUS_C));
+    PERIODIC_TICKS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Makes the RTC raise IRQ 8 at `frequency`, in Hz, which must be a power of two from 2 to 8192.
+///
+/// Other handlers may share the line, e.g. to run periodic work (see `irq::register_irq`).
+pub fn start_periodic(frequency: u32) -> Result<(), RtcError> {
+    if !frequency.is_power_of_two() || !(2..=8192).contains(&frequency) {
+        return Err(RtcError::InvalidFrequency);
+    }
+    let rate = (BASE_FREQUENCY / frequency).trailing_zeros() as u8 + 1; // The frequency is 32768 >> (rate - 1)
+    let mut handler = PERIODIC.lock();
+    if handler.is_none() {
+        *handler = Some(irq::register_irq(LINE, Handler::Fn(periodic_interrupt, 0)).map_err(RtcError::Irq)?);
+    }
+    with_cmos(|| {
+        write_register(STATUS_A, (read_register(STATUS_A) & !RATE) | rate);
+        write_register(STATUS_B, read_register(STATUS_B) | PERIODIC_ENABLE);
+        read_register(STATUS_C); // Until a pending interrupt is acknowledged, no other comes
+    });
+    Ok(())
+}
+
+/// Stops the periodic interrupts of the RTC.
+pub fn stop_periodic() -> Result<(), RtcError> {
+    with_cmos(|| write_register(STATUS_B, read_register(STATUS_B) & !PERIODIC_ENABLE));
+    match PERIODIC.lock().take() {
+        Some(handler) => irq::unregister(handler).map_err(RtcError::Irq),
+        None => Ok(()),
+    }
+}
+
+/// Number of periodic interrupts so far
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::SeqCst)
+}
+
+#[test_case]
+fn dates_convert_to_unix_time_and_back() {
+    let dates = [
+        ((1970, 1, 1, 0, 0, 0), 0),
+        ((1999, 12, 31, 23, 59, 59), 946_684_799),
+        ((2000, 3, 1, 0, 0, 0), 951_868_800),
+        ((2024, 2, 29, 12, 34, 56), 1_709_210_096),
+        ((2100, 12, 31, 23, 59, 59), 4_133_980_799),
+    ];
+    for ((year, month, day, hour, minute, second), unix) in dates {
+        let date = DateTime::new(year, month, day, hour, minute, second).unwrap();
+        assert_eq!(date.to_unix(), Some(unix), "{}", date);
+        assert_eq!(DateTime::from_unix(unix), date);
+    }
+    assert_eq!(DateTime::new(1969, 12, 31, 23, 59, 59).unwrap().to_unix(), None);
+    assert!(DateTime::new(2023, 2, 29, 0, 0, 0).is_none());
+    assert!(DateTime::new(2100, 2, 29, 0, 0, 0).is_none());
+}
+
+// QEMU is started with `-rtc base=2024-02-29T12:34:56` (see the bootimage test arguments)
+#[test_case]
+fn rtc_holds_the_date_qemu_was_given() {
+    let date = read().unwrap();
+    assert_eq!((date.year, date.month, date.day, date.hour), (2024, 2, 29, 12), "{}", date);
+    assert!((34..45).contains(&date.minute), "{}", date);
+}
+
+#[test_case]
+fn periodic_interrupts_follow_the_rate() {
+    use super::pit::busy_wait;
+    use core::time::Duration;
+
+    assert_eq!(start_periodic(1000), Err(RtcError::InvalidFrequency));
+    start_periodic(1024).unwrap();
+    let start = periodic_ticks();
+    busy_wait(Duration::from_millis(200));
+    let ticks = periodic_ticks() - start;
+    stop_periodic().unwrap();
+    assert!(ticks > 205 * 3 / 4 && ticks < 205 * 5 / 4, "{} interrupts instead of 205", ticks);
+
+    let stopped = periodic_ticks();
+    busy_wait(Duration::from_millis(20));
+    assert_eq!(periodic_ticks(), stopped);
+}
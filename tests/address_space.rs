@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(burritos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use burritos::memory::protect::with_user_access;
+use burritos::memory::{walk, AddressSpace, Translation, KERNEL_PAGING};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::allocator;
+    use burritos::memory::{self, BuddyFrameAllocator};
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+fn new_space() -> AddressSpace {
+    AddressSpace::new(KERNEL_PAGING.lock().as_mut().unwrap()).expect("address space creation failed")
+}
+
+#[test_case]
+fn regions_are_private_to_their_space() {
+    let mut space = new_space();
+    let region = space.allocate_region(KERNEL_PAGING.lock().as_mut().unwrap(), 2).unwrap();
+    assert!(matches!(walk::translate(region.start), Some(Translation::NotMapped { .. })));
+
+    unsafe { space.switch() };
+    assert!(space.is_active());
+    match walk::translate(region.start) {
+        Some(Translation::Mapped { flags, .. }) => assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE)),
+        other => panic!("region not mapped: {:?}", other),
+    }
+    let words = unsafe { core::slice::from_raw_parts_mut(region.start.as_mut_ptr::<u64>(), 2 * 512) };
+    with_user_access(|| {
+        assert!(words.iter().all(|&w| w == 0));
+        words.fill(0x4242);
+    });
+
+    // The kernel mappings are still there, heap growth included
+    let bytes = vec![0x17u8; 256 * 1024];
+    assert!(bytes.iter().all(|&b| b == 0x17));
+    drop(bytes);
+
+    space.free(KERNEL_PAGING.lock().as_mut().unwrap()); // Switches back to the kernel's table
+    assert!(matches!(walk::translate(region.start), Some(Translation::NotMapped { .. })));
+}
+
+#[test_case]
+fn spaces_are_freed() {
+    new_space().free(KERNEL_PAGING.lock().as_mut().unwrap()); // The kernel windows get their tables once and for all
+    let free_before = KERNEL_PAGING.lock().as_ref().unwrap().frame_allocator.free_frames();
+
+    let mut space = new_space();
+    {
+        let mut paging = KERNEL_PAGING.lock();
+        let paging = paging.as_mut().unwrap();
+        let region = space.allocate_region(paging, 4).unwrap();
+        space.allocate_region(paging, 1).unwrap();
+        space.free_region(paging, region).unwrap();
+        assert_eq!(space.regions().count(), 1);
+        space.free(paging);
+    }
+    assert_eq!(KERNEL_PAGING.lock().as_ref().unwrap().frame_allocator.free_frames(), free_before);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    burritos::test_panic_handler(info)
+}
@@ -0,0 +1,114 @@
+// Runs a user mode stub making a misaligned access with alignment checking enabled, which must end in the kernel's
+// alignment check handler. Alignment checks only happen in user mode, so the handler cannot resume a test as the
+// `raises!` ones of `interrupts::exceptions` do: this test expects the panic with its report instead.
+// Nothing else runs in user mode yet, so the kernel only sets it up with the `test-user-mode` feature:
+// cargo test --test alignment_check --features test-user-mode
+
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use burritos::memory::protect::with_user_access;
+use burritos::memory::{AddressSpace, KERNEL_PAGING};
+use burritos::{exit_qemu, gdt, hlt_loop, serial_print, serial_println, QemuExitCode};
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr::addr_of;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+// mov rax, [rsp + 1] then ud2, so that the stub faults anyway if the load did not
+const STUB: [u8; 7] = [0x48, 0x8b, 0x44, 0x24, 0x01, 0x0f, 0x0b];
+const HEADLINE: &str = "EXCEPTION: ALIGNMENT CHECK (vector 17)";
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::allocator;
+    use burritos::memory::{self, BuddyFrameAllocator};
+
+    serial_print!("alignment_check::alignment_check...\t");
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+
+    run_user_stub();
+}
+
+/// Switches to a fresh address space holding the stub and its stack, and returns to user mode into the stub.
+fn run_user_stub() -> ! {
+    #[repr(align(16))]
+    struct Stack([u8; KERNEL_STACK_SIZE]);
+    static mut KERNEL_STACK: Stack = Stack([0; KERNEL_STACK_SIZE]);
+
+    let mut space = AddressSpace::new(KERNEL_PAGING.lock().as_mut().unwrap()).expect("address space creation failed");
+    // The code on the first page, the stack on the second
+    let region = space.allocate_region(KERNEL_PAGING.lock().as_mut().unwrap(), 2).unwrap();
+    unsafe { space.switch() };
+    let code = region.start.as_mut_ptr::<u8>();
+    with_user_access(|| unsafe { code.copy_from_nonoverlapping(STUB.as_ptr(), STUB.len()) });
+    let user_stack = region.end() - 64u64;
+
+    // The exception comes from user mode, so its handler runs on the stack of the TSS
+    let kernel_stack = VirtAddr::from_ptr(unsafe { addr_of!(KERNEL_STACK.0) }) + KERNEL_STACK_SIZE as u64;
+    gdt::set_kernel_stack(kernel_stack);
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::ALIGNMENT_MASK)) };
+
+    let (code_selector, data_selector) = gdt::user_selectors();
+    // Interrupts stay disabled, as nothing else is set up to come back from user mode
+    let rflags = RFlags::ALIGNMENT_CHECK.bits() | 0x2; // Bit 1 is reserved, and always set
+    unsafe {
+        asm!(
+            "push {ss}",
+            "push {rsp}",
+            "push {rflags}",
+            "push {cs}",
+            "push {rip}",
+            "iretq",
+            ss = in(reg) u64::from(data_selector.0),
+            rsp = in(reg) user_stack.as_u64(),
+            rflags = in(reg) rflags,
+            cs = in(reg) u64::from(code_selector.0),
+            rip = in(reg) region.start.as_u64(),
+            options(noreturn)
+        );
+    }
+}
+
+/// Keeps the start of what is written to it, up to the length of `HEADLINE`.
+struct Headline {
+    bytes: [u8; HEADLINE.len()],
+    len: usize,
+}
+
+impl Write for Headline {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut headline = Headline {
+        bytes: [0; HEADLINE.len()],
+        len: 0,
+    };
+    let _ = write!(headline, "{}", info.message());
+    if headline.bytes == HEADLINE.as_bytes() {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        hlt_loop();
+    }
+    burritos::test_panic_handler(info)
+}
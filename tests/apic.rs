@@ -0,0 +1,76 @@
+// Interrupts once the local APIC and IO-APIC replace the 8259 PICs: ISA lines must be routed through the IO-APIC
+// (the PIT being wired to another input than its line on QEMU), and ended at the local APIC.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(burritos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use burritos::interrupts::{apic, irq, PICS};
+use burritos::time::{self, pit, Source};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::allocator;
+    use burritos::memory::{self, BuddyFrameAllocator};
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    apic::init().expect("APIC initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn pics_are_masked() {
+    assert!(apic::is_enabled());
+    assert_eq!(unsafe { PICS.lock().read_masks() }, [0xff, 0xff]);
+    apic::init().expect("second initialization failed");
+}
+
+#[test_case]
+fn timer_interrupts_keep_coming() {
+    static TICKS: AtomicU64 = AtomicU64::new(0);
+    fn tick(_: usize) {
+        TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Shared with the tick handler of `burritos::init`; a missing end of interrupt would stop them after the first
+    let handler = irq::register_irq(0, irq::Handler::Fn(tick, 0)).unwrap();
+    while TICKS.load(Ordering::SeqCst) < 3 {
+        x86_64::instructions::hlt();
+    }
+    irq::unregister(handler).unwrap();
+}
+
+#[test_case]
+fn apic_timer_takes_over() {
+    time::use_apic_timer().expect("APIC timer calibration failed");
+    assert_eq!(time::source(), Some(Source::ApicTimer));
+
+    let (ticks, start) = (time::ticks(), time::now());
+    pit::busy_wait(Duration::from_millis(200));
+    let ticks = time::ticks() - ticks;
+    let expected = u64::from(time::frequency()) / 5;
+    assert!(ticks > expected * 3 / 4 && ticks < expected * 5 / 4, "{} ticks instead of {}", ticks, expected);
+    let elapsed = start.elapsed();
+    assert!(elapsed > Duration::from_millis(150) && elapsed < Duration::from_millis(250), "{:?}", elapsed);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    burritos::test_panic_handler(info)
+}
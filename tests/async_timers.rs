@@ -0,0 +1,113 @@
+// Timer futures, run by the task executor: they must complete once their deadline passed, in deadline order,
+// even past the capacity of the pending queue, and leave no pending sleep behind once dropped.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(burritos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use burritos::task::task_executor::Executor;
+use burritos::task::timer::{self, interval, sleep, timeout, Elapsed};
+use burritos::task::Task;
+use burritos::time::Instant;
+use core::cell::RefCell;
+use core::panic::PanicInfo;
+use core::time::Duration;
+use futures_util::future::join_all;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::allocator;
+    use burritos::memory::{self, BuddyFrameAllocator};
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+const MS: Duration = Duration::from_millis(1);
+
+/// Runs `future` as the only task, and returns how long it took.
+fn run(future: impl core::future::Future<Output = ()> + 'static) -> Duration {
+    let start = Instant::now();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(future));
+    executor.run_until_done();
+    start.elapsed()
+}
+
+#[test_case]
+fn sleep_waits_for_its_duration() {
+    let elapsed = run(sleep(50 * MS));
+    assert!(elapsed >= 50 * MS, "slept for {:?}", elapsed);
+    assert_eq!(timer::pending(), 0);
+}
+
+#[test_case]
+fn sleeps_complete_in_deadline_order() {
+    let woken = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for delay in [30, 10, 20] {
+        let woken = woken.clone();
+        executor.spawn(Task::new(async move {
+            sleep(delay * MS).await;
+            woken.borrow_mut().push(delay);
+        }));
+    }
+    executor.run_until_done();
+    assert_eq!(*woken.borrow(), [10, 20, 30]);
+}
+
+// Those the pending queue cannot hold are polled again until they complete, rather than failing
+#[test_case]
+fn sleeps_past_the_queue_capacity_complete() {
+    let elapsed = run(async {
+        join_all((0..timer::MAX_TIMERS + 16).map(|_| sleep(20 * MS))).await;
+    });
+    assert!(elapsed >= 20 * MS, "slept for {:?}", elapsed);
+    assert_eq!(timer::pending(), 0);
+}
+
+#[test_case]
+fn interval_ticks_every_period() {
+    let elapsed = run(async {
+        let mut ticks = interval(10 * MS);
+        let mut last = ticks.tick().await;
+        for _ in 0..4 {
+            let tick = ticks.tick().await;
+            assert_eq!(tick - last, 10 * MS);
+            last = tick;
+        }
+    });
+    assert!(elapsed >= 50 * MS, "5 ticks in {:?}", elapsed);
+}
+
+#[test_case]
+fn timeout_drops_late_futures() {
+    let elapsed = run(async {
+        assert_eq!(timeout(sleep(100 * MS), 10 * MS).await, Err(Elapsed));
+        assert_eq!(timeout(async { 42 }, 10 * MS).await, Ok(42));
+    });
+    assert!(elapsed >= 10 * MS, "timed out after {:?}", elapsed);
+    assert_eq!(timer::pending(), 0);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    burritos::test_panic_handler(info)
+}
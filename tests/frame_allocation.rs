@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(burritos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use burritos::memory::{frame::MAX_ORDER, KERNEL_PAGING};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::allocator;
+    use burritos::memory::{self, BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn frames_are_distinct_and_freed() {
+    let mut frames: Vec<PhysFrame<Size4KiB>> = Vec::with_capacity(1000); // Heap can't grow while paging is locked
+    let mut paging = KERNEL_PAGING.lock();
+    let frame_allocator = &mut paging.as_mut().unwrap().frame_allocator;
+    let free_before = frame_allocator.free_frames();
+
+    for _ in 0..1000 {
+        frames.push(frame_allocator.allocate_frame().expect("out of frames"));
+    }
+    assert_eq!(frame_allocator.free_frames(), free_before - 1000);
+
+    frames.sort_unstable(); // Unlike `sort`, does not allocate
+    frames.dedup();
+    assert_eq!(frames.len(), 1000);
+
+    for frame in frames {
+        unsafe { FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, frame) };
+    }
+    assert_eq!(frame_allocator.free_frames(), free_before);
+}
+
+#[test_case]
+fn huge_frames_are_aligned() {
+    let mut paging = KERNEL_PAGING.lock();
+    let frame_allocator = &mut paging.as_mut().unwrap().frame_allocator;
+    let free_before = frame_allocator.free_frames();
+
+    let frame: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().expect("no 2 MiB frame left");
+    assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+    assert_eq!(frame_allocator.free_frames(), free_before - 512);
+
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.free_frames(), free_before);
+}
+
+// Small frames split from a 2 MiB block must merge back into one once all freed
+#[test_case]
+fn freed_frames_coalesce() {
+    let mut frames: Vec<PhysFrame<Size4KiB>> = Vec::with_capacity(2048);
+    let mut paging = KERNEL_PAGING.lock();
+    let frame_allocator = &mut paging.as_mut().unwrap().frame_allocator;
+    let huge_blocks_before = frame_allocator.free_blocks(MAX_ORDER);
+
+    for _ in 0..2048 {
+        frames.push(frame_allocator.allocate_frame().expect("out of frames"));
+    }
+    for frame in frames.into_iter().rev() {
+        unsafe { FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, frame) };
+    }
+    assert_eq!(frame_allocator.free_blocks(MAX_ORDER), huge_blocks_before);
+}
+
+#[test_case]
+fn shared_frames_are_freed_last() {
+    let mut paging = KERNEL_PAGING.lock();
+    let frame_allocator = &mut paging.as_mut().unwrap().frame_allocator;
+    let free_before = frame_allocator.free_frames();
+
+    let frame: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().expect("out of frames");
+    frame_allocator.share(frame);
+    frame_allocator.share(frame);
+    assert_eq!(frame_allocator.references(frame), 3);
+
+    for references in (1..3).rev() {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        assert_eq!(frame_allocator.references(frame), references);
+        assert_eq!(frame_allocator.free_frames(), free_before - 1);
+    }
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.free_frames(), free_before);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    burritos::test_panic_handler(info)
+}
@@ -0,0 +1,93 @@
+// Overflows a runtime-allocated kernel stack, which must hit its guard page and double fault
+// onto the guard-paged interrupt stack, instead of overwriting the memory below it.
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use burritos::memory::{KernelStack, KERNEL_PAGING};
+use burritos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use conquer_once::spin::OnceCell;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(burritos::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+static STACK: OnceCell<KernelStack> = OnceCell::uninit();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::allocator;
+    use burritos::memory::{self, BuddyFrameAllocator};
+
+    serial_print!("guarded_stack_overflow::guarded_stack_overflow...\t");
+
+    burritos::gdt::init();
+    TEST_IDT.load();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    burritos::gdt::init_ist_stacks().expect("interrupt stacks allocation failed");
+
+    let stack = KERNEL_PAGING.lock().as_mut().unwrap().allocate_stack(4).expect("stack allocation failed");
+    let top = stack.top();
+    STACK.init_once(|| stack);
+    unsafe {
+        asm!("mov rsp, {}", "call {}", in(reg) top.as_u64(), sym overflow_entry, options(noreturn));
+    }
+}
+
+extern "C" fn overflow_entry() -> ! {
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    let stack = STACK.get().unwrap();
+    let fault = Cr2::read().expect("invalid faulting address");
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+
+    // The fault is right below the stack, and is handled on another one
+    if fault < stack.bottom() && fault >= stack.bottom() - 4096u64 && !stack.contains(VirtAddr::new(rsp)) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\nFault at {:?}, stack {:?}..{:?}", fault, stack.bottom(), stack.top());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    burritos::test_panic_handler(info)
+}
@@ -1,3 +1,6 @@
+// Exercises the global allocator selected by the `alloc-*` features, e.g. for the bump allocator:
+// cargo test --test heap_allocation --no-default-features --features alloc-bump
+
 #![no_std]
 #![no_main]
 #![feature(custom_test_frameworks)]
@@ -10,26 +13,29 @@ use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use core::ptr::null_mut;
 use burritos::allocator::ALLOCATOR;
+use burritos::memory::KERNEL_PAGING;
+use burritos::serial_println;
 use core::alloc::{GlobalAlloc, Layout};
 use alloc::boxed::Box;
+use alloc::vec;
 use alloc::vec::Vec;
-use burritos::allocator::HEAP_SIZE;
+use burritos::allocator::{self, HEAP_MAX_SIZE, HEAP_SIZE};
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use burritos::allocator;
-    use burritos::memory::{self, BootInfoFrameAllocator};
+    use burritos::memory::{self, BuddyFrameAllocator};
     use x86_64::VirtAddr;
 
     burritos::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe {memory::init(phys_mem_offset)};
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    let mapper = unsafe {memory::init(phys_mem_offset)};
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
-    
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    serial_println!("Global allocator: {}", core::any::type_name::<allocator::GlobalAllocator>());
+
     test_main();
     loop{}
 }
@@ -71,6 +77,124 @@ fn manual_alloc() {
     }
 }
 
+// Fills the whole heap with blocks of varied sizes, frees everything and starts over: whatever the selected
+// allocator, freeing everything must give the whole heap back, so later rounds must fit as much as the first.
+// Merging of freed neighbours is checked on the linked-list allocator itself, by `freed_neighbours_coalesce`.
+#[test_case]
+fn varied_size_churn() {
+    const SIZES: &[usize] = &[32, 200, 48, 1000, 96, 4000, 512, 2048];
+    const MAX_BLOCKS: usize = 512;
+    let layout = |i: usize| Layout::from_size_align(SIZES[i % SIZES.len()], 8).unwrap();
+
+    allocator::set_heap_limit(allocator::heap_size()); // The heap must fill up rather than grow
+    // Kept off the heap, as the bump allocator only gives memory back once nothing is left allocated
+    let mut blocks = [null_mut(); MAX_BLOCKS];
+    let mut first_round_bytes = None;
+
+    for round in 0..20 {
+        let (mut allocated, mut count) = (0, 0);
+        while count < MAX_BLOCKS {
+            let ptr = unsafe { ALLOCATOR.alloc(layout(count)) };
+            if ptr.is_null() {
+                break;
+            }
+            allocated += layout(count).size();
+            blocks[count] = ptr;
+            count += 1;
+        }
+        // Frees in reverse order every other round, so that blocks are freed next to both free and used ones
+        for i in 0..count {
+            let i = if round % 2 == 0 { count - 1 - i } else { i };
+            unsafe { ALLOCATOR.dealloc(blocks[i], layout(i)) };
+        }
+
+        match first_round_bytes {
+            None => {
+                assert!(allocated >= HEAP_SIZE / 2, "only {} bytes could be allocated", allocated);
+                first_round_bytes = Some(allocated);
+            }
+            Some(bytes) => assert_eq!(allocated, bytes, "heap fragmented at round {}", round),
+        }
+    }
+    allocator::set_heap_limit(HEAP_MAX_SIZE);
+}
+
+#[test_case]
+fn heap_grows_on_demand() {
+    let big = vec![1u8; 4 * HEAP_SIZE];
+    assert_eq!(big.iter().map(|&b| b as usize).sum::<usize>(), 4 * HEAP_SIZE);
+    assert!(allocator::heap_size() > 4 * HEAP_SIZE);
+}
+
+// Growing the heap commits memory, which must fail cleanly rather than deadlock while the paging structures are
+// in use, whereas the committed pages must still get mapped on first access then
+#[test_case]
+fn no_growth_while_paging_is_locked() {
+    let layout = Layout::from_size_align(allocator::heap_size() + 3 * 4096, 8).unwrap(); // Cannot fit without growing
+    let paging = KERNEL_PAGING.lock();
+    let size_before = allocator::heap_size();
+    assert_eq!(unsafe { ALLOCATOR.alloc(layout) }, null_mut());
+    assert_eq!(allocator::heap_size(), size_before);
+    drop(paging);
+
+    unsafe {
+        let ptr = ALLOCATOR.alloc(layout);
+        assert_ne!(ptr, null_mut());
+        // A page away from both ends of the block and from the former end of the heap, where the allocator keeps its
+        // bookkeeping, so that the page is only mapped by this access (unless a huge page covers it)
+        let untouched = ptr.add(layout.size() - 2 * 4096);
+        let paging = KERNEL_PAGING.lock();
+        assert_eq!(untouched.read_volatile(), 0);
+        untouched.write(1);
+        drop(paging);
+        ALLOCATOR.dealloc(ptr, layout);
+    }
+}
+
+// Vec growth goes through realloc, which must keep the content wherever the block ends up
+#[test_case]
+fn realloc_keeps_content() {
+    let mut numbers: Vec<u64> = Vec::new();
+    for i in 0..2000 {
+        numbers.push(i);
+    }
+    assert!(numbers.iter().enumerate().all(|(i, &n)| n == i as u64));
+    numbers.truncate(10);
+    numbers.shrink_to_fit();
+    assert_eq!(numbers.iter().sum::<u64>(), 45);
+}
+
+#[test_case]
+fn alloc_zeroed_after_reuse() {
+    let layout = Layout::from_size_align(512, 8).unwrap();
+    unsafe {
+        let dirty = ALLOCATOR.alloc(layout);
+        dirty.write_bytes(0xff, layout.size());
+        ALLOCATOR.dealloc(dirty, layout); // Likely handed out again right away
+        let zeroed = ALLOCATOR.alloc_zeroed(layout);
+        assert!(core::slice::from_raw_parts(zeroed, layout.size()).iter().all(|&b| b == 0));
+        ALLOCATOR.dealloc(zeroed, layout);
+    }
+}
+
+#[test_case]
+fn no_leaks() {
+    let before = ALLOCATOR.stats();
+    {
+        let boxed = Box::new([7u64; 16]);
+        let numbers: Vec<u32> = (0..300).collect();
+        let boxes: Vec<Box<usize>> = (0..20).map(Box::new).collect();
+        assert_eq!(boxed[15] as usize + numbers[299] as usize + *boxes[19], 7 + 299 + 19);
+    }
+    let after = ALLOCATOR.stats();
+    serial_println!("{}", after);
+
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert_eq!(after.live_allocations(), before.live_allocations());
+    assert!(after.allocations >= before.allocations + 22);
+    assert!(after.peak_bytes_in_use >= before.bytes_in_use + 300 * 4);
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     burritos::test_panic_handler(info)
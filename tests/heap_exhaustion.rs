@@ -0,0 +1,71 @@
+// Allocates past a capped heap, which must end the run with `QemuExitCode::OutOfMemory` once the diagnostics of the
+// failed allocation are printed, rather than with a bare panic. Running out of memory is what this test expects, so
+// that exit code is turned into a success.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use burritos::allocator::{self, HEAP_SIZE};
+use burritos::{exit_qemu, hlt_loop, serial_print, serial_println, QemuExitCode};
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+const TOO_BIG: usize = 2 * HEAP_SIZE;
+const HEADLINE: &[u8] = b"Out of memory: allocation of 204800 B aligned on 1 B failed"; // Of `TOO_BIG` bytes
+const VGA_BUFFER: usize = 0xb8000;
+const VGA_HEIGHT: usize = 25;
+const VGA_WIDTH: usize = 80;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::memory::{self, BuddyFrameAllocator};
+
+    serial_print!("heap_exhaustion::heap_exhaustion...\t");
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+
+    allocator::set_alloc_error_hook(out_of_memory);
+    allocator::set_heap_limit(allocator::heap_size()); // The heap cannot grow to fit the allocation
+    black_box(Vec::<u8>::with_capacity(TOO_BIG));
+
+    panic!("Allocation past the heap limit succeeded");
+}
+
+fn out_of_memory(exit_code: QemuExitCode) -> ! {
+    let printed = (0..VGA_HEIGHT).any(|row| screen_line(row).starts_with(HEADLINE));
+    if exit_code == QemuExitCode::OutOfMemory && printed {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\nExit code {:?}, diagnostics on screen: {}", exit_code, printed);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
+
+/// Reads the characters of a row of the VGA text buffer, which the diagnostics are printed to.
+fn screen_line(row: usize) -> [u8; VGA_WIDTH] {
+    let cells = (VGA_BUFFER + row * VGA_WIDTH * 2) as *const u16;
+    let mut line = [0; VGA_WIDTH];
+    for (col, byte) in line.iter_mut().enumerate() {
+        *byte = unsafe { cells.add(col).read_volatile() } as u8; // The high byte is the color
+    }
+    line
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    burritos::test_panic_handler(info)
+}
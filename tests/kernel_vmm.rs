@@ -0,0 +1,175 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(burritos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use burritos::allocator::{HEAP_MAX_SIZE, HEAP_START};
+use burritos::memory::{walk, Region, RegionKind, Translation, VmmError, KERNEL_PAGING};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::allocator;
+    use burritos::memory::{self, BuddyFrameAllocator};
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn heap_region_is_reserved() {
+    let mut paging = KERNEL_PAGING.lock();
+    let paging = paging.as_mut().unwrap();
+    let heap = paging.vmm.find(VirtAddr::new(HEAP_START as u64)).expect("heap region missing");
+    assert_eq!(heap.kind, RegionKind::Heap);
+    assert_eq!(heap.size(), HEAP_MAX_SIZE as u64);
+
+    // Anything overlapping the heap, even by a page, is refused
+    let last_page = heap.end() - 4096u64;
+    match paging.vmm.reserve_at(last_page, 2, RegionKind::Task) {
+        Err(VmmError::Overlap(region)) => assert_eq!(region, heap),
+        other => panic!("overlap not detected: {:?}", other),
+    }
+}
+
+#[test_case]
+fn regions_are_mapped_and_freed() {
+    let mut paging = KERNEL_PAGING.lock();
+    let paging = paging.as_mut().unwrap();
+    let free_before = paging.frame_allocator.free_frames();
+
+    let first = paging.allocate_region(4, RegionKind::Task).expect("allocation failed");
+    let second = paging.allocate_region(4, RegionKind::Task).expect("allocation failed");
+    assert!(first.end() <= second.start || second.end() <= first.start);
+
+    let words = unsafe { core::slice::from_raw_parts_mut(first.start.as_mut_ptr::<u64>(), 4 * 512) };
+    assert!(words.iter().all(|&w| w == 0)); // Fresh frames are zeroed
+    words.fill(0x1234);
+
+    paging.free_region(first).unwrap();
+    paging.free_region(second).unwrap();
+    assert!(paging.vmm.find(first.start).is_none());
+    // Page tables created along the way are not given back
+    assert!(paging.frame_allocator.free_frames() >= free_before - 3);
+}
+
+#[test_case]
+fn lazy_regions_are_mapped_on_access() {
+    let (region, free_before) = {
+        let mut paging = KERNEL_PAGING.lock();
+        let paging = paging.as_mut().unwrap();
+        let region = paging.vmm.reserve(4, RegionKind::Task).unwrap();
+        (paging.vmm.make_lazy(region).unwrap(), paging.frame_allocator.free_frames())
+    };
+
+    // Each page faults once, with paging unlocked so that the handler can map it
+    let words = unsafe { core::slice::from_raw_parts_mut(region.start.as_mut_ptr::<u64>(), 4 * 512) };
+    assert!(words.iter().all(|&w| w == 0));
+    words.fill(0x5678);
+    assert!(words.iter().all(|&w| w == 0x5678));
+
+    let mut paging = KERNEL_PAGING.lock();
+    let paging = paging.as_mut().unwrap();
+    assert!(paging.frame_allocator.free_frames() <= free_before - 4);
+    paging.free_region(region).unwrap();
+}
+
+#[test_case]
+fn cloned_regions_are_copied_on_write() {
+    let (region, clone, free_before) = {
+        let mut paging = KERNEL_PAGING.lock();
+        let paging = paging.as_mut().unwrap();
+        let region = paging.allocate_region(2, RegionKind::Task).unwrap();
+        unsafe { core::slice::from_raw_parts_mut(region.start.as_mut_ptr::<u64>(), 2 * 512) }.fill(0x1111);
+        let free_before = paging.frame_allocator.free_frames();
+        let clone = paging.clone_region(&region).unwrap();
+        (region, clone, free_before)
+    };
+    let words = |region: Region| unsafe { core::slice::from_raw_parts_mut(region.start.as_mut_ptr::<u64>(), 2 * 512) };
+    assert!(words(clone).iter().all(|&w| w == 0x1111)); // Same frames, without copying
+
+    // Writes fault once per page, with paging unlocked so that the handler can copy it
+    words(clone)[0] = 0x2222;
+    words(region)[512] = 0x3333;
+    assert_eq!((words(region)[0], words(clone)[0]), (0x1111, 0x2222));
+    assert_eq!((words(region)[512], words(clone)[512]), (0x3333, 0x1111));
+
+    let mut paging = KERNEL_PAGING.lock();
+    let paging = paging.as_mut().unwrap();
+    // Each write copied its page, the other region keeping the shared frame
+    assert!(paging.frame_allocator.free_frames() <= free_before - 2);
+    paging.free_region(clone).unwrap();
+    paging.free_region(region).unwrap();
+}
+
+#[test_case]
+fn mmio_maps_the_device_frame() {
+    let mut paging = KERNEL_PAGING.lock();
+    let paging = paging.as_mut().unwrap();
+    let phys_mem_offset = paging.mapper.phys_offset();
+
+    // The VGA buffer, at an offset within its frame
+    let region = paging.map_mmio(PhysAddr::new(0xb8010), 16).expect("mapping failed");
+    assert_eq!(region.pages, 1);
+    let through_mmio = unsafe { region.start.as_ptr::<u8>().add(0x10).read_volatile() };
+    let through_offset = unsafe { (phys_mem_offset + 0xb8010u64).as_ptr::<u8>().read_volatile() };
+    assert_eq!(through_mmio, through_offset);
+
+    let free_before = paging.frame_allocator.free_frames();
+    paging.free_region(region).unwrap();
+    assert_eq!(paging.frame_allocator.free_frames(), free_before); // Device memory is not a free frame
+}
+
+#[test_case]
+fn big_ranges_use_huge_pages() {
+    let mut paging = KERNEL_PAGING.lock();
+    let paging = paging.as_mut().unwrap();
+    let free_before = paging.frame_allocator.free_frames();
+
+    // Far from the other regions, whose page tables would be in the way of huge pages
+    let start = VirtAddr::new(0x_7740_0000_0000);
+    let region = paging.vmm.reserve_at(start, 2 * 512, RegionKind::Task).unwrap();
+    paging.map_pages(&region, 0, region.pages).expect("mapping failed");
+    for offset in [0, 3 * 1024 * 1024] {
+        match walk::translate(region.start + offset) {
+            Some(Translation::Mapped { level, flags, .. }) => {
+                assert_eq!(level, 2);
+                assert!(flags.contains(PageTableFlags::HUGE_PAGE | PageTableFlags::NO_EXECUTE));
+            }
+            other => panic!("region not mapped: {:?}", other),
+        }
+    }
+    let words = unsafe { core::slice::from_raw_parts(region.start.as_ptr::<u64>(), 2 * 512 * 512) };
+    assert!(words.iter().all(|&w| w == 0));
+    paging.free_region(region).unwrap();
+    assert!(paging.frame_allocator.free_frames() >= free_before - 3);
+
+    // Device memory keeps its offset within huge pages
+    let region = paging.map_mmio(PhysAddr::new(0x20_1000), 4 * 1024 * 1024).expect("mapping failed");
+    assert_eq!(region.start.as_u64() % (2 * 1024 * 1024), 0x1000);
+    match walk::translate(region.start + 0x1f_f000u64) {
+        Some(Translation::Mapped { phys, level, .. }) => assert_eq!((phys, level), (PhysAddr::new(0x40_0000), 2)),
+        other => panic!("MMIO not mapped: {:?}", other),
+    }
+    paging.free_region(region).unwrap();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    burritos::test_panic_handler(info)
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(burritos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use burritos::allocator::HEAP_START;
+use burritos::memory::walk::{self, Mapping};
+use burritos::memory::{PageTableDump, RegionKind, Translation, KERNEL_PAGING};
+use burritos::serial_println;
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::allocator;
+    use burritos::memory::{self, BuddyFrameAllocator};
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn walk_stops_at_the_missing_level() {
+    let mut paging = KERNEL_PAGING.lock();
+    let paging = paging.as_mut().unwrap();
+
+    // Nothing was ever mapped in the task window, so not even its level 3 table exists
+    let region = paging.vmm.reserve(1, RegionKind::Task).unwrap();
+    assert_eq!(walk::translate(region.start), Some(Translation::NotMapped { level: 4 }));
+    paging.vmm.release(region).unwrap();
+
+    // Whereas only the page table entry is missing past the second page
+    let region = paging.allocate_region(1, RegionKind::Task).unwrap();
+    assert_eq!(walk::translate(region.end()), Some(Translation::NotMapped { level: 1 }));
+    paging.free_region(region).unwrap();
+}
+
+#[test_case]
+fn translate_follows_mappings() {
+    let mut paging = KERNEL_PAGING.lock();
+    let paging = paging.as_mut().unwrap();
+
+    let region = paging.map_mmio(PhysAddr::new(0xb8000), 4096).unwrap();
+    match walk::translate(region.start + 0x10u64) {
+        Some(Translation::Mapped { phys, level, flags }) => {
+            assert_eq!(phys, PhysAddr::new(0xb8010));
+            assert_eq!(level, 1);
+            assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+            assert!(!flags.contains(PageTableFlags::USER_ACCESSIBLE));
+        }
+        other => panic!("MMIO page not mapped: {:?}", other),
+    }
+    paging.free_region(region).unwrap();
+    assert!(matches!(walk::translate(region.start), Some(Translation::NotMapped { .. })));
+}
+
+#[test_case]
+fn mappings_are_merged_and_sorted() {
+    let region = KERNEL_PAGING.lock().as_mut().unwrap().allocate_region(8, RegionKind::Task).unwrap();
+
+    // Collected beforehand, as the heap cannot grow while paging is locked
+    let mut mappings: Vec<Mapping> = Vec::with_capacity(4096);
+    walk::for_each_mapping(|mapping| {
+        if mappings.len() < mappings.capacity() {
+            mappings.push(*mapping);
+        }
+    });
+    assert!(mappings.len() < mappings.capacity());
+
+    for pair in mappings.windows(2) {
+        let (first, second) = (pair[0], pair[1]);
+        assert!(first.start + first.size <= second.start, "overlapping or unsorted ranges");
+        let contiguous = first.start + first.size == second.start && first.phys + first.size == second.phys;
+        assert!(!contiguous || first.flags != second.flags, "ranges not merged");
+    }
+
+    // Fresh frames need not be contiguous, but the region is covered
+    let covered: u64 = mappings
+        .iter()
+        .filter(|mapping| region.contains(mapping.start))
+        .map(|mapping| mapping.size)
+        .sum();
+    assert_eq!(covered, region.size());
+    let heap = mappings.iter().find(|mapping| mapping.start == VirtAddr::new(HEAP_START as u64));
+    assert!(heap.is_some_and(|heap| heap.flags.contains(PageTableFlags::WRITABLE)));
+
+    KERNEL_PAGING.lock().as_mut().unwrap().free_region(region).unwrap();
+}
+
+#[test_case]
+fn dump_prints_without_locks() {
+    let _paging = KERNEL_PAGING.lock();
+    serial_println!("{}", PageTableDump);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    burritos::test_panic_handler(info)
+}
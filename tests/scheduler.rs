@@ -0,0 +1,195 @@
+// Scheduling policies: round-robin and fair-share must share the CPU evenly between busy threads, fair-share in
+// proportion to their nice values, and fixed priorities must run the highest ready thread first.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(burritos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use burritos::thread::scheduler::{FairShare, Params, Policy, Priority, RoundRobin, MAX_PRIORITY};
+use burritos::thread::{self, ThreadError};
+use burritos::time::Instant;
+use core::hint::spin_loop;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::allocator;
+    use burritos::memory::{self, BuddyFrameAllocator};
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    thread::init().expect("threads initialization failed");
+
+    test_main();
+    loop {}
+}
+
+const MS: Duration = Duration::from_millis(1);
+
+/// Runs `f` with `policy`, and the boot thread asking for `params`, then puts the defaults back.
+fn with_policy(policy: impl Policy + 'static, params: Params, f: impl FnOnce()) {
+    let boot = thread::current().unwrap();
+    thread::set_policy(Box::new(policy)).unwrap();
+    thread::set_params(boot, params).unwrap();
+    f();
+    thread::set_params(boot, Params::default()).unwrap();
+    thread::set_policy(Box::new(RoundRobin::new())).unwrap();
+}
+
+/// Runs a busy thread for each of `params` while sleeping for `duration`, and returns the time each ran for.
+fn spin_threads(params: &[Params], duration: Duration) -> Vec<Duration> {
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    STOP.store(false, Ordering::SeqCst);
+    let handles: Vec<_> = params
+        .iter()
+        .map(|&params| {
+            thread::spawn_with(params, || {
+                while !STOP.load(Ordering::SeqCst) {
+                    spin_loop();
+                }
+                thread::runtime(thread::current().unwrap()).unwrap()
+            })
+            .unwrap()
+        })
+        .collect();
+    thread::sleep(duration);
+    STOP.store(true, Ordering::SeqCst);
+    handles.into_iter().map(|handle| handle.join()).collect()
+}
+
+fn assert_even(runtimes: &[Duration]) {
+    let mean = runtimes.iter().sum::<Duration>() / runtimes.len() as u32;
+    for runtime in runtimes {
+        assert!(*runtime > mean * 3 / 4 && *runtime < mean * 5 / 4, "uneven runtimes {:?}", runtimes);
+    }
+}
+
+fn nice(nice: i8) -> Params {
+    Params { nice, ..Params::default() }
+}
+
+fn priority(priority: u8) -> Params {
+    Params { priority, ..Params::default() }
+}
+
+#[test_case]
+fn round_robin_shares_the_cpu_evenly() {
+    assert_eq!(thread::stats().unwrap().policy, "round-robin");
+    assert_even(&spin_threads(&[Params::default(); 3], 300 * MS));
+}
+
+#[test_case]
+fn fair_share_shares_the_cpu_evenly() {
+    with_policy(FairShare::new(), Params::default(), || {
+        assert_even(&spin_threads(&[Params::default(); 3], 300 * MS));
+    });
+}
+
+#[test_case]
+fn fair_share_follows_nice_values() {
+    with_policy(FairShare::new(), Params::default(), || {
+        let runtimes = spin_threads(&[nice(0), nice(5)], 400 * MS);
+        // The weights of nice 0 and 5 are 1024 and 335
+        let ratio = runtimes[0].as_micros() * 10 / runtimes[1].as_micros().max(1);
+        assert!((20..45).contains(&ratio), "runtimes {:?} instead of about 3 to 1", runtimes);
+    });
+}
+
+#[test_case]
+fn higher_priorities_run_first() {
+    static ORDER: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+    with_policy(Priority::new(), priority(MAX_PRIORITY), || {
+        let handles: Vec<_> = [5, 25, 10, 20]
+            .into_iter()
+            .map(|level| thread::spawn_with(priority(level), move || ORDER.lock().push(level)).unwrap())
+            .collect();
+        assert!(ORDER.lock().is_empty(), "a lower priority thread preempted the boot thread");
+        // Every other thread now has a higher priority than the boot thread
+        thread::set_params(thread::current().unwrap(), priority(0)).unwrap();
+        thread::yield_now();
+        assert_eq!(*ORDER.lock(), [25, 20, 10, 5]);
+        handles.into_iter().for_each(|handle| handle.join());
+    });
+}
+
+#[test_case]
+fn lower_priorities_wait_for_higher_ones() {
+    static LOW_SPINS: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    with_policy(Priority::new(), priority(MAX_PRIORITY), || {
+        let low = thread::spawn_with(priority(10), || {
+            while !STOP.load(Ordering::SeqCst) {
+                LOW_SPINS.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .unwrap();
+        let high = thread::spawn_with(priority(20), || {
+            let start = Instant::now();
+            while start.elapsed() < 50 * MS {
+                spin_loop();
+            }
+            LOW_SPINS.load(Ordering::SeqCst)
+        })
+        .unwrap();
+        assert_eq!(high.join(), 0, "the lower priority thread ran while a higher one was busy");
+        STOP.store(true, Ordering::SeqCst);
+        low.join();
+    });
+}
+
+#[test_case]
+fn stats_follow_the_run_queue() {
+    thread::sleep(MS); // Lets the idle thread free the threads of the previous tests, which spawning would do
+    thread::yield_now(); // Starts a new time slice, so that the threads are all spawned before any runs
+    let before = thread::stats().unwrap();
+    let handles: Vec<_> = (0..4).map(|_| thread::spawn(thread::yield_now).unwrap()).collect();
+    let spawned = thread::stats().unwrap();
+    assert!(spawned.ready >= 4 && spawned.max_ready >= 4, "{:?}", spawned);
+    assert_eq!(spawned.threads, before.threads + 4);
+    handles.into_iter().for_each(|handle| handle.join());
+
+    // Nothing else runs while the boot thread sleeps
+    thread::sleep(20 * MS);
+    let after = thread::stats().unwrap();
+    assert!(after.switches >= before.switches + 8, "{:?}", after);
+    assert!(after.idle >= before.idle + 15 * MS, "{:?}", after);
+    assert_eq!(after.ready, 0);
+}
+
+#[test_case]
+fn invalid_params_are_refused() {
+    let invalid = [priority(MAX_PRIORITY + 1), nice(20), nice(-21)];
+    for params in invalid {
+        assert!(matches!(thread::spawn_with(params, || {}), Err(ThreadError::InvalidParams)));
+    }
+    let handle = thread::spawn(|| {}).unwrap();
+    let id = handle.id();
+    assert_eq!(thread::params(id).unwrap(), Params::default());
+    handle.join();
+    assert!(matches!(thread::set_params(id, Params::default()), Err(ThreadError::NoSuchThread)));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    burritos::test_panic_handler(info)
+}
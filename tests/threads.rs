@@ -0,0 +1,137 @@
+// Kernel threads: they must return their value to whoever joins them, share the CPU even when they never yield,
+// sleep without holding it, and be freed once finished.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(burritos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use burritos::task::task_executor::Executor;
+use burritos::task::timer::sleep;
+use burritos::task::Task;
+use burritos::thread::{self, MAX_THREADS};
+use burritos::time::Instant;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::allocator;
+    use burritos::memory::{self, BuddyFrameAllocator};
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    thread::init().expect("threads initialization failed");
+
+    test_main();
+    loop {}
+}
+
+const MS: Duration = Duration::from_millis(1);
+
+#[test_case]
+fn joined_threads_return_their_value() {
+    let handles: Vec<_> = (0..8u64).map(|i| thread::spawn(move || i * i).unwrap()).collect();
+    let results: Vec<_> = handles.into_iter().map(|handle| handle.join()).collect();
+    assert_eq!(results, [0, 1, 4, 9, 16, 25, 36, 49]);
+}
+
+#[test_case]
+fn busy_threads_are_preempted() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    // Only ends once the other thread ran, which it never lets happen by itself
+    let spinner = thread::spawn(|| {
+        let mut spins = 0u64;
+        while !STOP.load(Ordering::SeqCst) {
+            spins += 1;
+        }
+        spins
+    })
+    .unwrap();
+    let stopper = thread::spawn(|| STOP.store(true, Ordering::SeqCst)).unwrap();
+    assert!(spinner.join() > 0);
+    stopper.join();
+}
+
+#[test_case]
+fn yielding_lets_other_threads_run() {
+    static RAN: AtomicU64 = AtomicU64::new(0);
+
+    let handle = thread::spawn(|| RAN.fetch_add(1, Ordering::SeqCst)).unwrap();
+    thread::yield_now();
+    assert_eq!(RAN.load(Ordering::SeqCst), 1);
+    assert!(handle.is_finished());
+    handle.join();
+}
+
+#[test_case]
+fn sleeping_threads_let_others_run() {
+    static WORK: AtomicU64 = AtomicU64::new(0);
+
+    let sleeper = thread::spawn(|| {
+        let start = Instant::now();
+        thread::sleep(50 * MS);
+        (start.elapsed(), WORK.load(Ordering::SeqCst))
+    })
+    .unwrap();
+    let start = Instant::now();
+    while !sleeper.is_finished() {
+        WORK.fetch_add(1, Ordering::SeqCst);
+    }
+    let (slept, work) = sleeper.join();
+    assert!(slept >= 50 * MS, "slept for {:?}", slept);
+    assert!(work > 0, "nothing ran while the thread slept");
+    assert!(start.elapsed() >= 50 * MS);
+}
+
+#[test_case]
+fn finished_threads_are_freed() {
+    for i in 0..MAX_THREADS as u64 * 2 {
+        assert_eq!(thread::spawn(move || i + 1).unwrap().join(), i + 1);
+    }
+    // Detached threads are freed too, once finished
+    for _ in 0..MAX_THREADS * 2 {
+        drop(thread::spawn(|| {}).unwrap());
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn executors_run_in_threads() {
+    let handles: Vec<_> = (1..=2u32)
+        .map(|i| {
+            thread::spawn(move || {
+                let start = Instant::now();
+                let mut executor = Executor::new();
+                executor.spawn(Task::new(sleep(i * 20 * MS)));
+                executor.spawn(Task::new(sleep(i * 10 * MS)));
+                executor.run_until_done();
+                start.elapsed()
+            })
+            .unwrap()
+        })
+        .collect();
+    for (i, handle) in (1..=2u32).zip(handles) {
+        let elapsed = handle.join();
+        assert!(elapsed >= i * 20 * MS, "ran for {:?}", elapsed);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    burritos::test_panic_handler(info)
+}
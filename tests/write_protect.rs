@@ -0,0 +1,60 @@
+// Writes to the kernel code, which must fault now that the kernel image is mapped with the rights of its segments,
+// instead of silently overwriting it.
+
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use burritos::memory::{walk, Translation};
+use burritos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+// Set right before writing, so that failed checks do not pass for the expected fault
+static WRITING: AtomicBool = AtomicBool::new(false);
+static READ_ONLY: [u8; 16] = [0x42; 16];
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use burritos::memory;
+
+    serial_print!("write_protect::write_to_code...\t");
+
+    burritos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let code = main as *const u8;
+    let flags = |addr: *const u8| match walk::translate(VirtAddr::from_ptr(addr)) {
+        Some(Translation::Mapped { flags, .. }) => flags,
+        other => panic!("kernel image not mapped: {:?}", other),
+    };
+    assert!(!flags(code).intersects(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    assert!(flags(READ_ONLY.as_ptr()).contains(PageTableFlags::NO_EXECUTE));
+    assert!(!flags(READ_ONLY.as_ptr()).contains(PageTableFlags::WRITABLE));
+    assert!(flags(&WRITING as *const AtomicBool as *const u8)
+        .contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+    WRITING.store(true, Ordering::SeqCst);
+    unsafe { (code as *mut u8).write_volatile(0xcc) };
+
+    serial_println!("[failed]\nwrite to code succeeded");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // The page fault handler panics on protection violations
+    if WRITING.load(Ordering::SeqCst) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}